@@ -1,5 +1,17 @@
 use anchor_lang::prelude::*;
-use anchor_spl::token::{self, Token, Transfer};
+use anchor_lang::solana_program::hash::{hash, hashv};
+use anchor_lang::system_program::{self, Allocate, Assign, CreateAccount};
+use anchor_spl::token::spl_token::native_mint;
+use anchor_spl::token_2022::spl_token_2022::{
+    self,
+    extension::{transfer_fee::TransferFeeConfig, BaseStateWithExtensions, StateWithExtensions},
+};
+use anchor_spl::token_2022_extensions::transfer_fee::{
+    harvest_withheld_tokens_to_mint, HarvestWithheldTokensToMint,
+};
+use anchor_spl::token_interface::{
+    self, CloseAccount, InitializeAccount3, Mint, TokenAccount, TokenInterface, TransferChecked,
+};
 
 declare_id!("DdCnHPAZi1kNJzZ9tSJvNz4nY11XsuzGZWsp6ASqtHpt");
 
@@ -7,64 +19,573 @@ declare_id!("DdCnHPAZi1kNJzZ9tSJvNz4nY11XsuzGZWsp6ASqtHpt");
 pub mod token_escrow {
     use super::*;
 
+    /// Create the global config holding the protocol fee and treasury
+    /// Only the program's upgrade authority can call this, and only once
+    pub fn initialize_config(
+        ctx: Context<InitializeConfig>,
+        fee_bps: u16,             // Share of every payment sent to the treasury
+        treasury: Pubkey,         // Wallet receiving protocol fees
+    ) -> Result<()> {
+        require!(fee_bps <= MAX_FEE_BPS, EscrowError::FeeTooHigh);
+
+        let config = &mut ctx.accounts.config;
+
+        config.admin = ctx.accounts.admin.key();
+        config.treasury = treasury;
+        config.fee_bps = fee_bps;
+        config.bump = ctx.bumps.config;
+        config.paused = false;
+        config.pending_admin = None;
+
+        msg!("Config initialized! Fee {} bps to treasury {}", fee_bps, treasury);
+
+        emit_cpi!(ConfigInitialized {
+            admin: config.admin,
+            treasury,
+            fee_bps,
+        });
+
+        Ok(())
+    }
+
+    /// Change the protocol fee and treasury
+    pub fn set_fee(ctx: Context<UpdateConfig>, fee_bps: u16, treasury: Pubkey) -> Result<()> {
+        require!(fee_bps <= MAX_FEE_BPS, EscrowError::FeeTooHigh);
+
+        let config = &mut ctx.accounts.config;
+
+        config.treasury = treasury;
+        config.fee_bps = fee_bps;
+
+        msg!("Fee updated! {} bps to treasury {}", fee_bps, treasury);
+
+        emit_cpi!(FeeUpdated { treasury, fee_bps });
+
+        Ok(())
+    }
+
+    /// Global kill switch, blocks new escrows and fills
+    /// `cancel` keeps working so users can always exit
+    pub fn set_paused(ctx: Context<UpdateConfig>, paused: bool) -> Result<()> {
+        ctx.accounts.config.paused = paused;
+
+        msg!("Program paused: {}", paused);
+
+        emit_cpi!(PausedSet { paused });
+
+        Ok(())
+    }
+
+    /// Start handing the admin role to a new key
+    /// The new admin has to accept before the rotation takes effect
+    pub fn transfer_admin(ctx: Context<UpdateConfig>, new_admin: Pubkey) -> Result<()> {
+        ctx.accounts.config.pending_admin = Some(new_admin);
+
+        msg!("Admin transfer started to {}", new_admin);
+
+        emit_cpi!(AdminTransferStarted {
+            admin: ctx.accounts.config.admin,
+            pending_admin: new_admin,
+        });
+
+        Ok(())
+    }
+
+    /// Complete an admin rotation started with `transfer_admin`
+    pub fn accept_admin(ctx: Context<AcceptAdmin>) -> Result<()> {
+        let config = &mut ctx.accounts.config;
+        let previous_admin = config.admin;
+
+        config.admin = ctx.accounts.new_admin.key();
+        config.pending_admin = None;
+
+        msg!("Admin transferred to {}", config.admin);
+
+        emit_cpi!(AdminTransferred {
+            previous_admin,
+            admin: config.admin,
+        });
+
+        Ok(())
+    }
+
     /// Initialize an escrow
     /// Alice locks her DED tokens and sets the exchange terms
+    #[allow(clippy::too_many_arguments)]
     pub fn initialize_escrow(
         ctx: Context<InitializeEscrow>,
+        offer_id: u64,            // Caller-chosen id, lets Alice run several escrows at once
         amount_to_send: u64,      // Amount of DED tokens Alice is offering
-        amount_to_receive: u64,   // Amount of SOL Alice wants in return (lamports)
+        amount_to_receive: u64,   // Lamports Alice wants, or payment mint base units if one is given
+        expires_at: Option<i64>,  // Optional unix timestamp after which the offer can't be filled
+        allowed_taker: Option<Pubkey>, // Optional counterparty, makes the escrow private
+        payouts: Vec<PayoutSplit>,     // Where proceeds go, empty means straight to Alice
+        dutch_auction: Option<DutchAuction>, // Optional time-decaying price, replaces `amount_to_receive`
+        arbiter: Option<Pubkey>,       // Optional third party who settles disputes, needs `allowed_taker`
     ) -> Result<()> {
+        require!(!ctx.accounts.config.paused, EscrowError::Paused);
+        require!(amount_to_send > 0, EscrowError::InvalidAmount);
+        require!(
+            payouts.is_empty()
+                || (payouts.len() <= MAX_PAYOUT_SPLITS
+                    && payouts.iter().all(|split| split.bps > 0)
+                    && payouts.iter().map(|split| split.bps as u64).sum::<u64>() == BPS_DENOMINATOR),
+            EscrowError::InvalidPayoutSplits
+        );
+
+        if let Some(expires_at) = expires_at {
+            let now = Clock::get()?.unix_timestamp;
+            require!(expires_at > now, EscrowError::InvalidExpiry);
+        }
+
+        // A Dutch auction starts at its start price and only ever goes down
+        if let Some(auction) = &dutch_auction {
+            require!(
+                auction.start_price >= auction.end_price
+                    && auction.end_ts > auction.start_ts
+                    && auction.step_interval >= 0,
+                EscrowError::InvalidAuction
+            );
+        }
+        let amount_to_receive = dutch_auction.map_or(amount_to_receive, |auction| auction.start_price);
+
+        // An arbiter settles disputes between Alice and a known taker, and can't be either of them
+        if let Some(arbiter) = arbiter {
+            require!(
+                allowed_taker.is_some_and(|taker| taker != arbiter)
+                    && arbiter != ctx.accounts.initializer.key(),
+                EscrowError::InvalidArbiter
+            );
+        }
+
+        let received = ctx.accounts.lock_tokens(
+            offer_id,
+            amount_to_send,
+            amount_to_receive,
+            EscrowMode::Offer,
+            &ctx.bumps,
+        )?;
+
         let escrow_account = &mut ctx.accounts.escrow_account;
+        escrow_account.expires_at = expires_at;
+        escrow_account.allowed_taker = allowed_taker;
+        escrow_account.payouts = payouts;
+        escrow_account.dutch_auction = dutch_auction;
+        escrow_account.arbiter = arbiter;
+        ctx.accounts.fit_escrow_account()?;
 
-        escrow_account.initializer = ctx.accounts.initializer.key();
-        escrow_account.initializer_token_account = ctx.accounts.initializer_token_account.key();
-        escrow_account.amount_to_send = amount_to_send;
-        escrow_account.amount_to_receive = amount_to_receive;
-        escrow_account.mint = ctx.accounts.mint.key();
-        escrow_account.escrow_bump = ctx.bumps.escrow_account;
-        escrow_account.vault_bump = ctx.bumps.vault;
-        escrow_account.is_completed = false;
+        let escrow_account = &ctx.accounts.escrow_account;
+        msg!("Escrow initialized! {} DED tokens locked", received);
+        match escrow_account.payment_mint {
+            None => msg!("Seller wants {} lamports (SOL)", amount_to_receive),
+            Some(payment_mint) => msg!("Seller wants {} of mint {}", amount_to_receive, payment_mint),
+        }
 
-        // Transfer tokens from Alice to escrow vault
-        let cpi_accounts = Transfer {
-            from: ctx.accounts.initializer_token_account.to_account_info(),
-            to: ctx.accounts.vault.to_account_info(),
-            authority: ctx.accounts.initializer.to_account_info(),
-        };
-        let cpi_program = ctx.accounts.token_program.to_account_info();
-        let cpi_ctx = CpiContext::new(cpi_program, cpi_accounts);
+        emit_cpi!(EscrowInitialized {
+            escrow: escrow_account.key(),
+            initializer: escrow_account.initializer,
+            offer_id,
+            mint: escrow_account.mint,
+            payment_mint: escrow_account.payment_mint,
+            amount_to_send: received,
+            amount_to_receive,
+            expires_at,
+            allowed_taker,
+            dutch_auction,
+            arbiter,
+        });
+
+        Ok(())
+    }
+
+    /// Fill the escrow, fully or partially
+    /// Bob pays SOL (or the payment mint) pro-rata and receives `fill_amount` of Alice's DED tokens
+    /// The expected terms protect Bob from the escrow changing under his pending transaction
+    /// Escrows with payout splits take the recipient accounts as remaining accounts, in order
+    pub fn exchange<'info>(
+        ctx: Context<'_, '_, 'info, 'info, Exchange<'info>>,
+        fill_amount: u64,         // Amount of DED tokens Bob takes out of the vault
+        max_payment: u64,         // Most Bob is willing to pay (lamports or payment mint units)
+        min_receive: u64,         // Least Bob accepts to receive after transfer fees
+        expected_mint: Pubkey,    // Mint Bob expects to receive
+    ) -> Result<()> {
+        require!(!ctx.accounts.config.paused, EscrowError::Paused);
+
+        let escrow_account = &ctx.accounts.escrow_account;
+
+        require!(escrow_account.is_offer(), EscrowError::InvalidEscrowMode);
+        require_keys_eq!(escrow_account.mint, expected_mint, EscrowError::MintMismatch);
+
+        // Verify escrow is not already completed or expired
+        require!(!escrow_account.is_completed, EscrowError::AlreadyCompleted);
+        require!(!escrow_account.disputed, EscrowError::Disputed);
+        let now = Clock::get()?.unix_timestamp;
+        require!(!escrow_account.is_expired(now), EscrowError::Expired);
+        require!(
+            fill_amount > 0 && fill_amount <= escrow_account.remaining_to_send,
+            EscrowError::InvalidFillAmount
+        );
+
+        // Price only what Bob will actually receive after any transfer fee
+        let withheld = transfer_fee(&ctx.accounts.mint, fill_amount)?;
+        let received_amount = fill_amount - withheld;
+        let payment = escrow_account.price_for(received_amount, now)?;
+        require!(
+            payment <= max_payment && received_amount >= min_receive,
+            EscrowError::SlippageExceeded
+        );
+
+        // The protocol fee comes out of Bob's payment, Alice gets the rest
+        let protocol_fee = ctx.accounts.config.fee_for(payment)?;
+        let initializer_share = payment - protocol_fee;
 
-        token::transfer(cpi_ctx, amount_to_send)?;
+        // Alice's share goes to her, or to her payout splits passed as remaining accounts
+        let payout_amounts = escrow_account.split_payout(initializer_share);
+        require!(
+            escrow_account.payouts.is_empty()
+                || ctx.remaining_accounts.len() == escrow_account.payouts.len(),
+            EscrowError::InvalidPayoutAccount
+        );
+
+        match escrow_account.payment_mint {
+            // Transfer SOL from taker (Bob) to initializer (Alice) and treasury.
+            // If Bob pays in wSOL it is first unwrapped into the escrow PDA's lamports.
+            None => {
+                let taker_info = ctx.accounts.taker.to_account_info();
+                let escrow_info = escrow_account.to_account_info();
+
+                let unwrap_deposit = if ctx.accounts.taker_payment_account.is_some() {
+                    let Some(unwrap_bump) = ctx.bumps.unwrap_account else {
+                        return err!(EscrowError::MissingPaymentAccount);
+                    };
+                    Some(ctx.accounts.unwrap_wsol_payment(payment, unwrap_bump)?)
+                } else {
+                    None
+                };
+
+                let pay = |to: &AccountInfo<'info>, amount: u64| match unwrap_deposit {
+                    Some(_) => withdraw_lamports(&escrow_info, to, amount),
+                    None => pay_lamports(&taker_info, to, amount),
+                };
+
+                if escrow_account.payouts.is_empty() {
+                    pay(&ctx.accounts.initializer.to_account_info(), initializer_share)?;
+                }
+
+                for ((split, amount), recipient) in escrow_account
+                    .payouts
+                    .iter()
+                    .zip(payout_amounts)
+                    .zip(ctx.remaining_accounts)
+                {
+                    require_keys_eq!(
+                        recipient.key(),
+                        split.recipient,
+                        EscrowError::InvalidPayoutAccount
+                    );
+
+                    pay(recipient, amount)?;
+                }
+
+                pay(&ctx.accounts.treasury.to_account_info(), protocol_fee)?;
+
+                // Hand the temporary account's rent back to Bob
+                if let Some(deposit) = unwrap_deposit {
+                    withdraw_lamports(&escrow_info, &taker_info, deposit)?;
+                }
+            }
+            // Transfer payment tokens from taker (Bob) to initializer (Alice) and treasury
+            Some(_) => {
+                let (Some(payment_mint), Some(taker_payment_account), Some(payment_token_program)) = (
+                    &ctx.accounts.payment_mint,
+                    &ctx.accounts.taker_payment_account,
+                    &ctx.accounts.payment_token_program,
+                ) else {
+                    return err!(EscrowError::MissingPaymentAccount);
+                };
+
+                if escrow_account.payouts.is_empty() {
+                    let Some(initializer_payment_account) = &ctx.accounts.initializer_payment_account
+                    else {
+                        return err!(EscrowError::MissingPaymentAccount);
+                    };
+
+                    let cpi_accounts = TransferChecked {
+                        from: taker_payment_account.to_account_info(),
+                        mint: payment_mint.to_account_info(),
+                        to: initializer_payment_account.to_account_info(),
+                        authority: ctx.accounts.taker.to_account_info(),
+                    };
+                    let cpi_program = payment_token_program.to_account_info();
+                    let cpi_ctx = CpiContext::new(cpi_program, cpi_accounts);
+
+                    token_interface::transfer_checked(cpi_ctx, initializer_share, payment_mint.decimals)?;
+                }
+
+                for ((split, amount), recipient) in escrow_account
+                    .payouts
+                    .iter()
+                    .zip(payout_amounts)
+                    .zip(ctx.remaining_accounts)
+                {
+                    let recipient_account = InterfaceAccount::<TokenAccount>::try_from(recipient)?;
+                    require_keys_eq!(
+                        recipient_account.owner,
+                        split.recipient,
+                        EscrowError::InvalidPayoutAccount
+                    );
+                    require!(
+                        Some(recipient_account.mint) == escrow_account.payment_mint,
+                        EscrowError::PaymentMintMismatch
+                    );
+
+                    let cpi_accounts = TransferChecked {
+                        from: taker_payment_account.to_account_info(),
+                        mint: payment_mint.to_account_info(),
+                        to: recipient.clone(),
+                        authority: ctx.accounts.taker.to_account_info(),
+                    };
+                    let cpi_program = payment_token_program.to_account_info();
+                    let cpi_ctx = CpiContext::new(cpi_program, cpi_accounts);
+
+                    token_interface::transfer_checked(cpi_ctx, amount, payment_mint.decimals)?;
+                }
+
+                if protocol_fee > 0 {
+                    let Some(treasury_payment_account) = &ctx.accounts.treasury_payment_account else {
+                        return err!(EscrowError::MissingPaymentAccount);
+                    };
 
-        msg!("Escrow initialized! {} DED tokens locked", amount_to_send);
-        msg!("Seller wants {} lamports (SOL)", amount_to_receive);
+                    let cpi_accounts = TransferChecked {
+                        from: taker_payment_account.to_account_info(),
+                        mint: payment_mint.to_account_info(),
+                        to: treasury_payment_account.to_account_info(),
+                        authority: ctx.accounts.taker.to_account_info(),
+                    };
+                    let cpi_program = payment_token_program.to_account_info();
+                    let cpi_ctx = CpiContext::new(cpi_program, cpi_accounts);
+
+                    token_interface::transfer_checked(cpi_ctx, protocol_fee, payment_mint.decimals)?;
+                }
+            }
+        }
+
+        // Transfer DED tokens from vault to taker (Bob)
+        transfer_from_vault(
+            escrow_account,
+            &ctx.accounts.vault,
+            &ctx.accounts.mint,
+            ctx.accounts.taker_token_account.to_account_info(),
+            &ctx.accounts.token_program,
+            fill_amount,
+        )?;
+
+        let remaining_to_send = escrow_account.remaining_to_send - fill_amount;
+
+        emit_cpi!(EscrowExchanged {
+            escrow: escrow_account.key(),
+            initializer: escrow_account.initializer,
+            taker: ctx.accounts.taker.key(),
+            mint: escrow_account.mint,
+            payment_mint: escrow_account.payment_mint,
+            fill_amount,
+            received_amount,
+            payment,
+            protocol_fee,
+            remaining_to_send,
+        });
+
+        if remaining_to_send == 0 {
+            // Close the now empty vault and the escrow, rent goes back to initializer (Alice)
+            close_vault(
+                escrow_account,
+                &ctx.accounts.vault,
+                &ctx.accounts.mint,
+                ctx.accounts.initializer.to_account_info(),
+                &ctx.accounts.token_program,
+            )?;
+
+            ctx.accounts
+                .escrow_account
+                .close(ctx.accounts.initializer.to_account_info())?;
+
+            msg!("Escrow completed! Tokens and SOL exchanged");
+        } else {
+            ctx.accounts.escrow_account.remaining_to_send = remaining_to_send;
+
+            msg!(
+                "Escrow partially filled! {} DED tokens for {} lamports, {} remaining",
+                fill_amount,
+                payment,
+                remaining_to_send
+            );
+        }
 
         Ok(())
     }
 
-    /// Complete the escrow
-    /// Bob pays SOL and receives Alice's DED tokens
-    pub fn exchange(ctx: Context<Exchange>) -> Result<()> {
+    /// Amend an open escrow
+    /// Alice can reprice it, top up the vault or withdraw part of the remaining tokens.
+    /// The escrow is rebased on what is left in the vault, so after partial fills
+    /// `amount_to_send` becomes the remaining inventory.
+    pub fn update_escrow(
+        ctx: Context<UpdateEscrow>,
+        amount_to_receive: Option<u64>, // New price for all tokens left in the vault after the update
+        deposit_amount: u64,            // DED tokens to add to the vault
+        withdraw_amount: u64,           // DED tokens to take back out of the vault
+    ) -> Result<()> {
         let escrow_account = &ctx.accounts.escrow_account;
 
-        // Verify escrow is not already completed
         require!(!escrow_account.is_completed, EscrowError::AlreadyCompleted);
+        require!(!escrow_account.disputed, EscrowError::Disputed);
+        require!(escrow_account.is_offer(), EscrowError::InvalidEscrowMode);
+        require!(
+            escrow_account.dutch_auction.is_none(),
+            EscrowError::AuctionTermsLocked
+        );
+        require!(
+            deposit_amount == 0 || withdraw_amount == 0,
+            EscrowError::InvalidAmount
+        );
+        require!(
+            withdraw_amount < escrow_account.remaining_to_send,
+            EscrowError::InvalidAmount
+        );
+        require!(
+            withdraw_amount == 0 || escrow_account.arbiter.is_none(),
+            EscrowError::ArbitratedEscrow
+        );
+        let now = Clock::get()?.unix_timestamp;
+        require!(
+            deposit_amount == 0 || !escrow_account.is_expired(now),
+            EscrowError::Expired
+        );
+
+        if deposit_amount > 0 {
+            // Transfer more tokens from Alice to escrow vault
+            let cpi_accounts = TransferChecked {
+                from: ctx.accounts.initializer_token_account.to_account_info(),
+                mint: ctx.accounts.mint.to_account_info(),
+                to: ctx.accounts.vault.to_account_info(),
+                authority: ctx.accounts.initializer.to_account_info(),
+            };
+            let cpi_program = ctx.accounts.token_program.to_account_info();
+            let cpi_ctx = CpiContext::new(cpi_program, cpi_accounts);
+
+            token_interface::transfer_checked(cpi_ctx, deposit_amount, ctx.accounts.mint.decimals)?;
+        }
+
+        if withdraw_amount > 0 {
+            // Return part of the tokens to Alice
+            transfer_from_vault(
+                escrow_account,
+                &ctx.accounts.vault,
+                &ctx.accounts.mint,
+                ctx.accounts.initializer_token_account.to_account_info(),
+                &ctx.accounts.token_program,
+                withdraw_amount,
+            )?;
+        }
+
+        // Book the real vault balance change, transfer-fee mints deliver less than sent
+        ctx.accounts.vault.reload()?;
+        let balance_after = ctx.accounts.vault.amount;
+
+        let escrow_account = &mut ctx.accounts.escrow_account;
 
-        // Transfer SOL from taker (Bob) to initializer (Alice)
-        let ix = anchor_lang::solana_program::system_instruction::transfer(
-            &ctx.accounts.taker.key(),
-            &ctx.accounts.initializer.key(),
+        // Without a new price, keep the per-token price and round in Alice's favor
+        let amount_to_receive = match amount_to_receive {
+            Some(amount_to_receive) => amount_to_receive,
+            None => escrow_account.price_for(balance_after, now)?,
+        };
+
+        // Rebase on the remaining inventory, tokens already filled are out of the picture
+        escrow_account.amount_to_send = balance_after;
+        escrow_account.remaining_to_send = balance_after;
+        escrow_account.amount_to_receive = amount_to_receive;
+
+        msg!(
+            "Escrow updated! {} DED tokens for {} in total, {} remaining",
+            escrow_account.amount_to_send,
             escrow_account.amount_to_receive,
+            escrow_account.remaining_to_send
         );
-        anchor_lang::solana_program::program::invoke(
-            &ix,
-            &[
-                ctx.accounts.taker.to_account_info(),
-                ctx.accounts.initializer.to_account_info(),
-            ],
+
+        emit_cpi!(EscrowUpdated {
+            escrow: escrow_account.key(),
+            initializer: escrow_account.initializer,
+            amount_to_send: escrow_account.amount_to_send,
+            amount_to_receive: escrow_account.amount_to_receive,
+            remaining_to_send: escrow_account.remaining_to_send,
+        });
+
+        Ok(())
+    }
+
+    /// Cancel the escrow and return tokens to Alice
+    /// Drains and closes the vault, rent from both accounts goes back to Alice
+    pub fn cancel(ctx: Context<Cancel>) -> Result<()> {
+        let escrow_account = &ctx.accounts.escrow_account;
+
+        // Verify escrow is not already completed
+        require!(!escrow_account.is_completed, EscrowError::AlreadyCompleted);
+        require!(!escrow_account.disputed, EscrowError::Disputed);
+        // The taker relies on the arbiter, Alice can't pull the tokens out from under them.
+        // She gets a refund by raising a dispute for the arbiter to resolve.
+        require!(escrow_account.arbiter.is_none(), EscrowError::ArbitratedEscrow);
+
+        // Once someone has bid, an auction can only be settled.
+        // An HTLC only refunds once the counterparty's claim window is over.
+        // A multisig escrow only refunds with its approvers' consent.
+        // A vesting grant only gives back its unvested part through `revoke`.
+        match &escrow_account.mode {
+            EscrowMode::EnglishAuction(auction) => {
+                require!(auction.highest_bidder.is_none(), EscrowError::AuctionHasBids);
+            }
+            EscrowMode::SealedBidAuction(auction) => {
+                require!(auction.bid_count == 0, EscrowError::AuctionHasBids);
+            }
+            EscrowMode::Htlc(htlc) => {
+                let now = Clock::get()?.unix_timestamp;
+                require!(now >= htlc.timelock, EscrowError::TimelockNotExpired);
+            }
+            EscrowMode::Multisig(_) | EscrowMode::Vesting(_) => {
+                return err!(EscrowError::InvalidEscrowMode)
+            }
+            EscrowMode::Offer | EscrowMode::Milestones(_) => {}
+        }
+
+        // Return tokens to initializer
+        drain_and_close_vault(
+            escrow_account,
+            &ctx.accounts.vault,
+            &ctx.accounts.mint,
+            ctx.accounts.initializer_token_account.to_account_info(),
+            ctx.accounts.initializer.to_account_info(),
+            &ctx.accounts.token_program,
         )?;
 
-        // Transfer DED tokens from vault to taker (Bob)
+        msg!("Escrow cancelled! Tokens returned");
+
+        emit_cpi!(EscrowCancelled {
+            escrow: escrow_account.key(),
+            initializer: escrow_account.initializer,
+            mint: escrow_account.mint,
+            amount_returned: ctx.accounts.vault.amount,
+        });
+
+        Ok(())
+    }
+
+    /// Recover rent from a legacy escrow left open in the completed state
+    /// Escrows created before `offer_id` existed were only flagged as completed
+    /// by `exchange` instead of being closed, which locked their PDA seeds for good
+    pub fn close_completed(ctx: Context<CloseCompleted>) -> Result<()> {
+        let escrow_account = LegacyEscrowAccount::load(&ctx.accounts.escrow_account)?;
+
+        require!(escrow_account.is_completed, EscrowError::NotCompleted);
+
+        // Legacy vaults are seeded without an offer id
         let seeds = &[
             b"vault",
             escrow_account.initializer.as_ref(),
@@ -72,33 +593,47 @@ pub mod token_escrow {
         ];
         let signer = &[&seeds[..]];
 
-        let cpi_accounts = Transfer {
-            from: ctx.accounts.vault.to_account_info(),
-            to: ctx.accounts.taker_token_account.to_account_info(),
+        // Close the empty vault, rent goes back to initializer
+        let cpi_accounts = CloseAccount {
+            account: ctx.accounts.vault.to_account_info(),
+            destination: ctx.accounts.initializer.to_account_info(),
             authority: ctx.accounts.vault.to_account_info(),
         };
         let cpi_program = ctx.accounts.token_program.to_account_info();
         let cpi_ctx = CpiContext::new_with_signer(cpi_program, cpi_accounts, signer);
 
-        token::transfer(cpi_ctx, escrow_account.amount_to_send)?;
+        token_interface::close_account(cpi_ctx)?;
 
-        // Mark escrow as completed
-        let escrow_account = &mut ctx.accounts.escrow_account;
-        escrow_account.is_completed = true;
+        LegacyEscrowAccount::close(
+            &ctx.accounts.escrow_account.to_account_info(),
+            &ctx.accounts.initializer.to_account_info(),
+        )?;
+
+        msg!("Completed escrow closed! Rent returned");
 
-        msg!("Escrow completed! Tokens and SOL exchanged");
+        emit_cpi!(CompletedEscrowClosed {
+            escrow: ctx.accounts.escrow_account.key(),
+            initializer: escrow_account.initializer,
+        });
 
         Ok(())
     }
 
-    /// Cancel the escrow and return tokens to Alice
-    pub fn cancel(ctx: Context<Cancel>) -> Result<()> {
-        let escrow_account = &ctx.accounts.escrow_account;
+    /// Cancel a legacy escrow that was never filled
+    /// Same as `cancel` for escrows created before `offer_id` existed,
+    /// which the current account layout and seeds can't load
+    pub fn cancel_legacy(ctx: Context<CancelLegacy>) -> Result<()> {
+        let escrow_account = LegacyEscrowAccount::load(&ctx.accounts.escrow_account)?;
 
-        // Verify escrow is not already completed
         require!(!escrow_account.is_completed, EscrowError::AlreadyCompleted);
+        require_keys_eq!(
+            ctx.accounts.initializer_token_account.key(),
+            escrow_account.initializer_token_account,
+            EscrowError::InvalidLegacyEscrow
+        );
+        require_keys_eq!(ctx.accounts.mint.key(), escrow_account.mint, EscrowError::MintMismatch);
 
-        // Return tokens to initializer
+        // Legacy vaults are seeded without an offer id
         let seeds = &[
             b"vault",
             escrow_account.initializer.as_ref(),
@@ -106,141 +641,3747 @@ pub mod token_escrow {
         ];
         let signer = &[&seeds[..]];
 
-        let cpi_accounts = Transfer {
+        // Return tokens to initializer
+        let cpi_accounts = TransferChecked {
             from: ctx.accounts.vault.to_account_info(),
+            mint: ctx.accounts.mint.to_account_info(),
             to: ctx.accounts.initializer_token_account.to_account_info(),
             authority: ctx.accounts.vault.to_account_info(),
         };
         let cpi_program = ctx.accounts.token_program.to_account_info();
         let cpi_ctx = CpiContext::new_with_signer(cpi_program, cpi_accounts, signer);
 
-        token::transfer(cpi_ctx, escrow_account.amount_to_send)?;
+        token_interface::transfer_checked(cpi_ctx, ctx.accounts.vault.amount, ctx.accounts.mint.decimals)?;
 
-        msg!("Escrow cancelled! Tokens returned");
+        // Close the empty vault, rent goes back to initializer
+        let cpi_accounts = CloseAccount {
+            account: ctx.accounts.vault.to_account_info(),
+            destination: ctx.accounts.initializer.to_account_info(),
+            authority: ctx.accounts.vault.to_account_info(),
+        };
+        let cpi_program = ctx.accounts.token_program.to_account_info();
+        let cpi_ctx = CpiContext::new_with_signer(cpi_program, cpi_accounts, signer);
+
+        token_interface::close_account(cpi_ctx)?;
+
+        LegacyEscrowAccount::close(
+            &ctx.accounts.escrow_account.to_account_info(),
+            &ctx.accounts.initializer.to_account_info(),
+        )?;
+
+        msg!("Legacy escrow cancelled! Tokens returned");
+
+        emit_cpi!(EscrowCancelled {
+            escrow: ctx.accounts.escrow_account.key(),
+            initializer: escrow_account.initializer,
+            mint: escrow_account.mint,
+            amount_returned: ctx.accounts.vault.amount,
+        });
 
         Ok(())
     }
-}
 
-#[derive(Accounts)]
-pub struct InitializeEscrow<'info> {
-    #[account(mut)]
-    pub initializer: Signer<'info>,
+    /// Return the tokens of an expired escrow to Alice
+    /// Permissionless, anyone can crank this once `expires_at` has passed
+    pub fn reclaim_expired(ctx: Context<ReclaimExpired>) -> Result<()> {
+        let escrow_account = &ctx.accounts.escrow_account;
 
-    pub mint: Account<'info, anchor_spl::token::Mint>,
+        require!(!escrow_account.is_completed, EscrowError::AlreadyCompleted);
+        require!(!escrow_account.disputed, EscrowError::Disputed);
+        let now = Clock::get()?.unix_timestamp;
+        require!(escrow_account.is_expired(now), EscrowError::NotExpired);
 
-    #[account(
-        mut,
-        constraint = initializer_token_account.owner == initializer.key(),
-        constraint = initializer_token_account.mint == mint.key()
-    )]
-    pub initializer_token_account: Account<'info, anchor_spl::token::TokenAccount>,
+        // Return tokens to the initializer's original token account
+        drain_and_close_vault(
+            escrow_account,
+            &ctx.accounts.vault,
+            &ctx.accounts.mint,
+            ctx.accounts.initializer_token_account.to_account_info(),
+            ctx.accounts.initializer.to_account_info(),
+            &ctx.accounts.token_program,
+        )?;
 
-    #[account(
-        init,
-        payer = initializer,
-        space = 8 + EscrowAccount::INIT_SPACE,
-        seeds = [b"escrow", initializer.key().as_ref()],
-        bump
-    )]
-    pub escrow_account: Account<'info, EscrowAccount>,
+        msg!("Expired escrow reclaimed! Tokens returned");
 
-    #[account(
-        init,
-        payer = initializer,
-        seeds = [b"vault", initializer.key().as_ref()],
-        bump,
-        token::mint = mint,
-        token::authority = vault,
-    )]
-    pub vault: Account<'info, anchor_spl::token::TokenAccount>,
+        emit_cpi!(EscrowExpiredReclaimed {
+            escrow: escrow_account.key(),
+            initializer: escrow_account.initializer,
+            caller: ctx.accounts.caller.key(),
+            mint: escrow_account.mint,
+            amount_returned: ctx.accounts.vault.amount,
+        });
 
-    pub token_program: Program<'info, Token>,
-    pub system_program: Program<'info, System>,
-}
+        Ok(())
+    }
 
-#[derive(Accounts)]
-pub struct Exchange<'info> {
-    #[account(mut)]
-    pub taker: Signer<'info>,
+    /// Post a standing buy order
+    /// The buyer locks SOL in a bid PDA and states how many tokens of a mint they want
+    pub fn initialize_bid(
+        ctx: Context<InitializeBid>,
+        offer_id: u64,            // Caller-chosen id, lets the buyer run several bids at once
+        token_amount: u64,        // Amount of tokens the buyer wants to receive
+        lamports: u64,            // Amount of SOL the buyer locks as payment
+    ) -> Result<()> {
+        require!(!ctx.accounts.config.paused, EscrowError::Paused);
+        require!(token_amount > 0 && lamports > 0, EscrowError::InvalidAmount);
 
-    /// CHECK: This is the initializer who will receive SOL
-    #[account(mut)]
-    pub initializer: UncheckedAccount<'info>,
+        let bid_escrow = &mut ctx.accounts.bid_escrow;
 
-    #[account(
-        mut,
-        constraint = taker_token_account.owner == taker.key(),
-        constraint = taker_token_account.mint == escrow_account.mint
-    )]
-    pub taker_token_account: Account<'info, anchor_spl::token::TokenAccount>,
+        bid_escrow.buyer = ctx.accounts.buyer.key();
+        bid_escrow.offer_id = offer_id;
+        bid_escrow.buyer_token_account = ctx.accounts.buyer_token_account.key();
+        bid_escrow.mint = ctx.accounts.mint.key();
+        bid_escrow.token_amount = token_amount;
+        bid_escrow.lamports = lamports;
+        bid_escrow.bump = ctx.bumps.bid_escrow;
 
-    #[account(
-        mut,
-        seeds = [b"vault", escrow_account.initializer.as_ref()],
-        bump = escrow_account.vault_bump,
-    )]
-    pub vault: Account<'info, anchor_spl::token::TokenAccount>,
+        // Lock the buyer's SOL in the bid PDA, on top of its rent
+        pay_lamports(
+            &ctx.accounts.buyer.to_account_info(),
+            &bid_escrow.to_account_info(),
+            lamports,
+        )?;
 
-    #[account(
-        mut,
-        seeds = [b"escrow", escrow_account.initializer.as_ref()],
-        bump = escrow_account.escrow_bump,
-        has_one = initializer,
-        has_one = mint,
-    )]
-    pub escrow_account: Account<'info, EscrowAccount>,
+        msg!("Bid initialized! {} lamports locked for {} tokens", lamports, token_amount);
 
-    pub mint: Account<'info, anchor_spl::token::Mint>,
-    pub token_program: Program<'info, Token>,
-    pub system_program: Program<'info, System>,
-}
+        emit_cpi!(BidInitialized {
+            bid: bid_escrow.key(),
+            buyer: bid_escrow.buyer,
+            offer_id,
+            mint: bid_escrow.mint,
+            token_amount,
+            lamports,
+        });
 
-#[derive(Accounts)]
-pub struct Cancel<'info> {
-    #[account(mut)]
-    pub initializer: Signer<'info>,
+        Ok(())
+    }
 
-    #[account(
-        mut,
-        constraint = initializer_token_account.owner == initializer.key(),
-    )]
-    pub initializer_token_account: Account<'info, anchor_spl::token::TokenAccount>,
+    /// Fill a standing buy order
+    /// The seller sends the tokens to the buyer and receives the locked SOL
+    pub fn fill_bid(
+        ctx: Context<FillBid>,
+        max_send: u64,            // Most tokens the seller is willing to send, transfer fee included
+        min_payment: u64,         // Least SOL the seller accepts after the protocol fee
+        expected_mint: Pubkey,    // Mint the seller expects to deliver
+    ) -> Result<()> {
+        require!(!ctx.accounts.config.paused, EscrowError::Paused);
 
-    #[account(
-        mut,
-        seeds = [b"vault", escrow_account.initializer.as_ref()],
-        bump = escrow_account.vault_bump,
-    )]
-    pub vault: Account<'info, anchor_spl::token::TokenAccount>,
+        let bid_escrow = &ctx.accounts.bid_escrow;
 
-    #[account(
-        mut,
-        seeds = [b"escrow", initializer.key().as_ref()],
-        bump = escrow_account.escrow_bump,
-        has_one = initializer,
-        close = initializer
-    )]
-    pub escrow_account: Account<'info, EscrowAccount>,
+        require_keys_eq!(bid_escrow.mint, expected_mint, EscrowError::MintMismatch);
 
-    pub token_program: Program<'info, Token>,
-}
+        // The buyer gets the full amount, the seller covers any transfer fee
+        let send_amount = amount_with_transfer_fee(&ctx.accounts.mint, bid_escrow.token_amount)?;
+        let protocol_fee = ctx.accounts.config.fee_for(bid_escrow.lamports)?;
+        let seller_share = bid_escrow.lamports - protocol_fee;
+        require!(
+            send_amount <= max_send && seller_share >= min_payment,
+            EscrowError::SlippageExceeded
+        );
 
-#[account]
-#[derive(InitSpace)]
-pub struct EscrowAccount {
-    pub initializer: Pubkey,
-    pub initializer_token_account: Pubkey,
-    pub amount_to_send: u64,
-    pub amount_to_receive: u64,
-    pub mint: Pubkey,
-    pub escrow_bump: u8,
-    pub vault_bump: u8,
-    pub is_completed: bool,
-}
+        // Transfer tokens from seller to buyer
+        let cpi_accounts = TransferChecked {
+            from: ctx.accounts.seller_token_account.to_account_info(),
+            mint: ctx.accounts.mint.to_account_info(),
+            to: ctx.accounts.buyer_token_account.to_account_info(),
+            authority: ctx.accounts.seller.to_account_info(),
+        };
+        let cpi_program = ctx.accounts.token_program.to_account_info();
+        let cpi_ctx = CpiContext::new(cpi_program, cpi_accounts);
 
-#[error_code]
-pub enum EscrowError {
-    #[msg("Escrow has already been completed")]
-    AlreadyCompleted,
-}
\ No newline at end of file
+        token_interface::transfer_checked(cpi_ctx, send_amount, ctx.accounts.mint.decimals)?;
+
+        // Pay the seller and treasury out of the bid PDA.
+        // Its rent goes back to the buyer through the `close` constraint.
+        withdraw_lamports(
+            &bid_escrow.to_account_info(),
+            &ctx.accounts.seller.to_account_info(),
+            seller_share,
+        )?;
+        withdraw_lamports(
+            &bid_escrow.to_account_info(),
+            &ctx.accounts.treasury.to_account_info(),
+            protocol_fee,
+        )?;
+
+        msg!("Bid filled! Tokens and SOL exchanged");
+
+        emit_cpi!(BidFilled {
+            bid: bid_escrow.key(),
+            buyer: bid_escrow.buyer,
+            seller: ctx.accounts.seller.key(),
+            mint: bid_escrow.mint,
+            token_amount: bid_escrow.token_amount,
+            lamports: bid_escrow.lamports,
+            protocol_fee,
+        });
+
+        Ok(())
+    }
+
+    /// Cancel a standing buy order
+    /// Closes the bid PDA, the locked SOL and rent go back to the buyer
+    pub fn cancel_bid(ctx: Context<CancelBid>) -> Result<()> {
+        let bid_escrow = &ctx.accounts.bid_escrow;
+
+        msg!("Bid cancelled! {} lamports returned", bid_escrow.lamports);
+
+        emit_cpi!(BidCancelled {
+            bid: bid_escrow.key(),
+            buyer: bid_escrow.buyer,
+            lamports_returned: bid_escrow.lamports,
+        });
+
+        Ok(())
+    }
+
+    /// Quote what `exchange` would charge right now for `fill_amount` tokens
+    /// Mostly useful for Dutch auctions, call it as a view from clients
+    pub fn current_price(ctx: Context<CurrentPrice>, fill_amount: u64) -> Result<u64> {
+        let escrow_account = &ctx.accounts.escrow_account;
+
+        require!(escrow_account.is_offer(), EscrowError::InvalidEscrowMode);
+        require!(
+            fill_amount > 0 && fill_amount <= escrow_account.remaining_to_send,
+            EscrowError::InvalidFillAmount
+        );
+
+        let now = Clock::get()?.unix_timestamp;
+        let withheld = transfer_fee(&ctx.accounts.mint, fill_amount)?;
+
+        escrow_account.price_for(fill_amount - withheld, now)
+    }
+
+    /// Open an English auction on the vault contents
+    /// Alice locks her DED tokens, bidders then compete in SOL until `end_ts`
+    pub fn initialize_english_auction(
+        ctx: Context<InitializeEscrow>,
+        offer_id: u64,            // Caller-chosen id, shares the escrow id space
+        amount_to_send: u64,      // Amount of DED tokens being auctioned
+        reserve_price: u64,       // Lowest opening bid in lamports
+        min_increment: u64,       // Least each new bid has to add on top of the highest one
+        end_ts: i64,              // Unix timestamp after which no bids are accepted
+    ) -> Result<()> {
+        require!(!ctx.accounts.config.paused, EscrowError::Paused);
+        require!(amount_to_send > 0, EscrowError::InvalidAmount);
+        // Bids are escrowed as lamports, auctions can't be priced in another mint
+        require!(ctx.accounts.payment_mint.is_none(), EscrowError::PaymentMintMismatch);
+
+        let now = Clock::get()?.unix_timestamp;
+        require!(
+            reserve_price > 0 && min_increment > 0 && end_ts > now,
+            EscrowError::InvalidAuction
+        );
+
+        let mode = EscrowMode::EnglishAuction(EnglishAuction {
+            reserve_price,
+            min_increment,
+            end_ts,
+            highest_bidder: None,
+            highest_bid: 0,
+        });
+        let received = ctx.accounts.lock_tokens(offer_id, amount_to_send, reserve_price, mode, &ctx.bumps)?;
+        let escrow_account = &ctx.accounts.escrow_account;
+
+        msg!("Auction opened! {} DED tokens, reserve {} lamports", received, reserve_price);
+
+        emit_cpi!(EnglishAuctionInitialized {
+            escrow: escrow_account.key(),
+            initializer: escrow_account.initializer,
+            offer_id,
+            mint: escrow_account.mint,
+            amount_to_send: received,
+            reserve_price,
+            min_increment,
+            end_ts,
+        });
+
+        Ok(())
+    }
+
+    /// Bid on an English auction
+    /// The bid's lamports are locked in a bid PDA, and the bidder being outbid
+    /// gets theirs back in the same transaction. The highest bidder can raise
+    /// their own bid, `amount` is then the new total and only the difference is locked.
+    pub fn place_bid(ctx: Context<PlaceBid>, amount: u64) -> Result<()> {
+        require!(!ctx.accounts.config.paused, EscrowError::Paused);
+
+        let EscrowMode::EnglishAuction(auction) = ctx.accounts.escrow_account.mode else {
+            return err!(EscrowError::InvalidEscrowMode);
+        };
+
+        let now = Clock::get()?.unix_timestamp;
+        require!(now < auction.end_ts, EscrowError::AuctionEnded);
+
+        let min_bid = match auction.highest_bidder {
+            None => auction.reserve_price,
+            Some(_) => auction
+                .highest_bid
+                .checked_add(auction.min_increment)
+                .ok_or(EscrowError::MathOverflow)?,
+        };
+        require!(amount >= min_bid, EscrowError::BidTooLow);
+
+        let bidder = ctx.accounts.bidder.key();
+        let outbid = auction.highest_bidder.filter(|highest_bidder| *highest_bidder != bidder);
+
+        // Refund the bidder being outbid, closing their bid PDA returns the bid and its rent
+        if let Some(highest_bidder) = outbid {
+            let (Some(previous_bid), Some(previous_bidder)) =
+                (&ctx.accounts.previous_bid, &ctx.accounts.previous_bidder)
+            else {
+                return err!(EscrowError::InvalidBidAccount);
+            };
+            require_keys_eq!(previous_bid.bidder, highest_bidder, EscrowError::InvalidBidAccount);
+            require_keys_eq!(previous_bidder.key(), highest_bidder, EscrowError::InvalidBidAccount);
+
+            previous_bid.close(previous_bidder.to_account_info())?;
+        }
+
+        // Lock the new bid on top of the bid PDA's rent, a raise only adds the difference.
+        // Only the highest bidder has a bid PDA, outbid ones are closed above.
+        let locked = ctx.accounts.auction_bid.amount;
+        pay_lamports(
+            &ctx.accounts.bidder.to_account_info(),
+            &ctx.accounts.auction_bid.to_account_info(),
+            amount - locked,
+        )?;
+
+        let auction_bid = &mut ctx.accounts.auction_bid;
+        auction_bid.escrow = ctx.accounts.escrow_account.key();
+        auction_bid.bidder = bidder;
+        auction_bid.amount = amount;
+        auction_bid.bump = ctx.bumps.auction_bid;
+
+        ctx.accounts.escrow_account.mode = EscrowMode::EnglishAuction(EnglishAuction {
+            highest_bidder: Some(bidder),
+            highest_bid: amount,
+            ..auction
+        });
+
+        msg!("Bid placed! {} lamports", amount);
+
+        emit_cpi!(AuctionBidPlaced {
+            escrow: ctx.accounts.escrow_account.key(),
+            bidder,
+            amount,
+            outbid,
+        });
+
+        Ok(())
+    }
+
+    /// Settle an English auction once it has ended
+    /// Permissionless. The highest bidder gets the tokens and Alice the winning bid,
+    /// without any bid the tokens go back to Alice. Not blocked by the pause switch,
+    /// once there is a bid this is the only way out for the bidder and Alice.
+    pub fn settle_auction(ctx: Context<SettleAuction>) -> Result<()> {
+        let escrow_account = &ctx.accounts.escrow_account;
+
+        let EscrowMode::EnglishAuction(auction) = escrow_account.mode else {
+            return err!(EscrowError::InvalidEscrowMode);
+        };
+
+        let now = Clock::get()?.unix_timestamp;
+        require!(now >= auction.end_ts, EscrowError::AuctionNotEnded);
+
+        let token_amount = ctx.accounts.vault.amount;
+        let mut protocol_fee = 0;
+
+        // Bids are only taken at or above the reserve, so any bid means the reserve was met
+        let destination = match auction.highest_bidder {
+            Some(highest_bidder) => {
+                let (Some(winner), Some(winner_token_account), Some(winning_bid)) = (
+                    &ctx.accounts.winner,
+                    &ctx.accounts.winner_token_account,
+                    &ctx.accounts.winning_bid,
+                ) else {
+                    return err!(EscrowError::InvalidBidAccount);
+                };
+                require_keys_eq!(winner.key(), highest_bidder, EscrowError::InvalidBidAccount);
+                require_keys_eq!(winning_bid.bidder, highest_bidder, EscrowError::InvalidBidAccount);
+                require_keys_eq!(winner_token_account.owner, highest_bidder, EscrowError::InvalidBidAccount);
+                require_keys_eq!(winner_token_account.mint, escrow_account.mint, EscrowError::MintMismatch);
+
+                // Pay Alice and the treasury out of the winning bid PDA,
+                // its rent goes back to the winner
+                protocol_fee = ctx.accounts.config.fee_for(winning_bid.amount)?;
+                withdraw_lamports(
+                    &winning_bid.to_account_info(),
+                    &ctx.accounts.initializer.to_account_info(),
+                    winning_bid.amount - protocol_fee,
+                )?;
+                withdraw_lamports(
+                    &winning_bid.to_account_info(),
+                    &ctx.accounts.treasury.to_account_info(),
+                    protocol_fee,
+                )?;
+                winning_bid.close(winner.to_account_info())?;
+
+                winner_token_account.to_account_info()
+            }
+            None => ctx.accounts.initializer_token_account.to_account_info(),
+        };
+
+        // Transfer the auctioned tokens out of the vault
+        drain_and_close_vault(
+            escrow_account,
+            &ctx.accounts.vault,
+            &ctx.accounts.mint,
+            destination,
+            ctx.accounts.initializer.to_account_info(),
+            &ctx.accounts.token_program,
+        )?;
+
+        match auction.highest_bidder {
+            Some(_) => msg!("Auction settled! Sold for {} lamports", auction.highest_bid),
+            None => msg!("Auction settled without bids! Tokens returned"),
+        }
+
+        emit_cpi!(AuctionSettled {
+            escrow: escrow_account.key(),
+            initializer: escrow_account.initializer,
+            winner: auction.highest_bidder,
+            winning_bid: auction.highest_bid,
+            token_amount,
+            protocol_fee,
+        });
+
+        Ok(())
+    }
+
+    /// Open a sealed-bid auction on the vault contents
+    /// Bidders commit to hidden bids until `commit_end_ts` and reveal them until `reveal_end_ts`
+    #[allow(clippy::too_many_arguments)]
+    pub fn initialize_sealed_auction(
+        ctx: Context<InitializeEscrow>,
+        offer_id: u64,            // Caller-chosen id, shares the escrow id space
+        amount_to_send: u64,      // Amount of DED tokens being auctioned
+        reserve_price: u64,       // Lowest winning bid in lamports
+        commit_end_ts: i64,       // Unix timestamp ending the commit phase
+        reveal_end_ts: i64,       // Unix timestamp ending the reveal phase
+        second_price: bool,       // Winner pays the runner-up's bid instead of their own
+    ) -> Result<()> {
+        require!(!ctx.accounts.config.paused, EscrowError::Paused);
+        require!(amount_to_send > 0, EscrowError::InvalidAmount);
+        // Deposits are escrowed as lamports, auctions can't be priced in another mint
+        require!(ctx.accounts.payment_mint.is_none(), EscrowError::PaymentMintMismatch);
+
+        let now = Clock::get()?.unix_timestamp;
+        require!(
+            reserve_price > 0 && commit_end_ts > now && reveal_end_ts > commit_end_ts,
+            EscrowError::InvalidAuction
+        );
+
+        let mode = EscrowMode::SealedBidAuction(SealedBidAuction {
+            reserve_price,
+            commit_end_ts,
+            reveal_end_ts,
+            second_price,
+            bid_count: 0,
+            highest_bidder: None,
+            highest_bid: 0,
+            second_bid: 0,
+        });
+        let received = ctx.accounts.lock_tokens(offer_id, amount_to_send, reserve_price, mode, &ctx.bumps)?;
+        let escrow_account = &ctx.accounts.escrow_account;
+
+        msg!("Sealed auction opened! {} DED tokens, reserve {} lamports", received, reserve_price);
+
+        emit_cpi!(SealedAuctionInitialized {
+            escrow: escrow_account.key(),
+            initializer: escrow_account.initializer,
+            offer_id,
+            mint: escrow_account.mint,
+            amount_to_send: received,
+            reserve_price,
+            commit_end_ts,
+            reveal_end_ts,
+            second_price,
+        });
+
+        Ok(())
+    }
+
+    /// Commit to a sealed bid
+    /// `commitment` is `sha256(amount_le || salt || bidder)`. The deposit has to cover
+    /// the bid, depositing more than the bid keeps the amount hidden.
+    pub fn commit_bid(ctx: Context<CommitBid>, commitment: [u8; 32], deposit: u64) -> Result<()> {
+        require!(!ctx.accounts.config.paused, EscrowError::Paused);
+
+        let EscrowMode::SealedBidAuction(mut auction) = ctx.accounts.escrow_account.mode else {
+            return err!(EscrowError::InvalidEscrowMode);
+        };
+
+        let now = Clock::get()?.unix_timestamp;
+        require!(now < auction.commit_end_ts, EscrowError::AuctionEnded);
+        require!(deposit >= auction.reserve_price, EscrowError::BidTooLow);
+
+        // Lock the deposit on top of the bid PDA's rent
+        pay_lamports(
+            &ctx.accounts.bidder.to_account_info(),
+            &ctx.accounts.sealed_bid.to_account_info(),
+            deposit,
+        )?;
+
+        let sealed_bid = &mut ctx.accounts.sealed_bid;
+        sealed_bid.escrow = ctx.accounts.escrow_account.key();
+        sealed_bid.bidder = ctx.accounts.bidder.key();
+        sealed_bid.commitment = commitment;
+        sealed_bid.deposit = deposit;
+        sealed_bid.revealed = false;
+        sealed_bid.amount = 0;
+        sealed_bid.bump = ctx.bumps.sealed_bid;
+
+        auction.bid_count = auction.bid_count.checked_add(1).ok_or(EscrowError::MathOverflow)?;
+        ctx.accounts.escrow_account.mode = EscrowMode::SealedBidAuction(auction);
+
+        msg!("Sealed bid committed! {} lamports deposited", deposit);
+
+        emit_cpi!(SealedBidCommitted {
+            escrow: ctx.accounts.escrow_account.key(),
+            bidder: ctx.accounts.bidder.key(),
+            deposit,
+        });
+
+        Ok(())
+    }
+
+    /// Reveal a sealed bid during the reveal phase
+    /// Not blocked by the pause switch, unrevealed deposits are forfeited
+    pub fn reveal_bid(ctx: Context<RevealBid>, amount: u64, salt: [u8; 32]) -> Result<()> {
+        let EscrowMode::SealedBidAuction(mut auction) = ctx.accounts.escrow_account.mode else {
+            return err!(EscrowError::InvalidEscrowMode);
+        };
+
+        let now = Clock::get()?.unix_timestamp;
+        require!(
+            now >= auction.commit_end_ts && now < auction.reveal_end_ts,
+            EscrowError::NotRevealPhase
+        );
+
+        let sealed_bid = &mut ctx.accounts.sealed_bid;
+
+        require!(!sealed_bid.revealed, EscrowError::BidAlreadyRevealed);
+        let commitment = hashv(&[&amount.to_le_bytes(), &salt, sealed_bid.bidder.as_ref()]);
+        require!(
+            commitment.to_bytes() == sealed_bid.commitment,
+            EscrowError::InvalidCommitment
+        );
+        require!(amount <= sealed_bid.deposit, EscrowError::DepositTooLow);
+
+        sealed_bid.revealed = true;
+        sealed_bid.amount = amount;
+
+        // Bids below the reserve are revealed but can't win, ties go to the earlier reveal
+        if amount >= auction.reserve_price {
+            if auction.highest_bidder.is_none() || amount > auction.highest_bid {
+                auction.second_bid = auction.highest_bid;
+                auction.highest_bid = amount;
+                auction.highest_bidder = Some(sealed_bid.bidder);
+            } else if amount > auction.second_bid {
+                auction.second_bid = amount;
+            }
+        }
+        ctx.accounts.escrow_account.mode = EscrowMode::SealedBidAuction(auction);
+
+        msg!("Sealed bid revealed! {} lamports", amount);
+
+        emit_cpi!(SealedBidRevealed {
+            escrow: ctx.accounts.escrow_account.key(),
+            bidder: ctx.accounts.bidder.key(),
+            amount,
+        });
+
+        Ok(())
+    }
+
+    /// Settle a sealed-bid auction once the reveal phase is over
+    /// Permissionless. The highest revealed bid gets the tokens and pays the clearing price,
+    /// without a winning bid the tokens go back to Alice. Not blocked by the pause switch,
+    /// the winner's deposit and the vault have no other way out.
+    pub fn settle_sealed_auction(ctx: Context<SettleSealedAuction>) -> Result<()> {
+        let escrow_account = &ctx.accounts.escrow_account;
+
+        require!(!escrow_account.is_completed, EscrowError::AlreadyCompleted);
+        let EscrowMode::SealedBidAuction(mut auction) = escrow_account.mode else {
+            return err!(EscrowError::InvalidEscrowMode);
+        };
+
+        let now = Clock::get()?.unix_timestamp;
+        require!(now >= auction.reveal_end_ts, EscrowError::AuctionNotEnded);
+
+        let token_amount = ctx.accounts.vault.amount;
+        let price = auction.clearing_price();
+        let mut protocol_fee = 0;
+
+        let destination = match auction.highest_bidder {
+            Some(highest_bidder) => {
+                let (Some(winner), Some(winner_token_account), Some(winning_bid)) = (
+                    &ctx.accounts.winner,
+                    &ctx.accounts.winner_token_account,
+                    &ctx.accounts.winning_bid,
+                ) else {
+                    return err!(EscrowError::InvalidBidAccount);
+                };
+                require_keys_eq!(winner.key(), highest_bidder, EscrowError::InvalidBidAccount);
+                require_keys_eq!(winning_bid.bidder, highest_bidder, EscrowError::InvalidBidAccount);
+                require_keys_eq!(winner_token_account.owner, highest_bidder, EscrowError::InvalidBidAccount);
+                require_keys_eq!(winner_token_account.mint, escrow_account.mint, EscrowError::MintMismatch);
+
+                // Pay Alice and the treasury out of the winning deposit,
+                // the rest of it and the rent go back to the winner
+                protocol_fee = ctx.accounts.config.fee_for(price)?;
+                withdraw_lamports(
+                    &winning_bid.to_account_info(),
+                    &ctx.accounts.initializer.to_account_info(),
+                    price - protocol_fee,
+                )?;
+                withdraw_lamports(
+                    &winning_bid.to_account_info(),
+                    &ctx.accounts.treasury.to_account_info(),
+                    protocol_fee,
+                )?;
+                winning_bid.close(winner.to_account_info())?;
+                auction.bid_count -= 1;
+
+                winner_token_account.to_account_info()
+            }
+            None => ctx.accounts.initializer_token_account.to_account_info(),
+        };
+
+        // Transfer the auctioned tokens out of the vault
+        drain_and_close_vault(
+            escrow_account,
+            &ctx.accounts.vault,
+            &ctx.accounts.mint,
+            destination,
+            ctx.accounts.initializer.to_account_info(),
+            &ctx.accounts.token_program,
+        )?;
+
+        match auction.highest_bidder {
+            Some(_) => msg!("Sealed auction settled! Sold for {} lamports", price),
+            None => msg!("Sealed auction settled without a winning bid! Tokens returned"),
+        }
+
+        emit_cpi!(SealedAuctionSettled {
+            escrow: escrow_account.key(),
+            initializer: escrow_account.initializer,
+            winner: auction.highest_bidder,
+            price,
+            token_amount,
+            protocol_fee,
+        });
+
+        // The other bids are closed one by one through `close_sealed_bid`,
+        // the escrow account goes with the last of them
+        let escrow_account = &mut ctx.accounts.escrow_account;
+        escrow_account.is_completed = true;
+        escrow_account.remaining_to_send = 0;
+        escrow_account.mode = EscrowMode::SealedBidAuction(auction);
+
+        if auction.bid_count == 0 {
+            escrow_account.close(ctx.accounts.initializer.to_account_info())?;
+        }
+
+        Ok(())
+    }
+
+    /// Close a losing or unrevealed sealed bid once the reveal phase is over
+    /// Permissionless. Revealed deposits go back to the bidder, unrevealed ones are
+    /// forfeited to Alice. The bid PDA's rent always goes back to the bidder.
+    pub fn close_sealed_bid(ctx: Context<CloseSealedBid>) -> Result<()> {
+        let EscrowMode::SealedBidAuction(mut auction) = ctx.accounts.escrow_account.mode else {
+            return err!(EscrowError::InvalidEscrowMode);
+        };
+
+        let now = Clock::get()?.unix_timestamp;
+        require!(now >= auction.reveal_end_ts, EscrowError::AuctionNotEnded);
+
+        let sealed_bid = &ctx.accounts.sealed_bid;
+
+        // The winning bid is paid out by `settle_sealed_auction`
+        require!(
+            auction.highest_bidder != Some(sealed_bid.bidder),
+            EscrowError::InvalidBidAccount
+        );
+
+        let forfeited = if sealed_bid.revealed { 0 } else { sealed_bid.deposit };
+        let refunded = sealed_bid.deposit - forfeited;
+
+        // The rest of the bid PDA goes back to the bidder through the `close` constraint
+        withdraw_lamports(
+            &sealed_bid.to_account_info(),
+            &ctx.accounts.initializer.to_account_info(),
+            forfeited,
+        )?;
+
+        auction.bid_count -= 1;
+
+        msg!("Sealed bid closed! {} lamports refunded, {} forfeited", refunded, forfeited);
+
+        emit_cpi!(SealedBidClosed {
+            escrow: ctx.accounts.escrow_account.key(),
+            bidder: sealed_bid.bidder,
+            refunded,
+            forfeited,
+        });
+
+        let escrow_account = &mut ctx.accounts.escrow_account;
+        escrow_account.mode = EscrowMode::SealedBidAuction(auction);
+
+        // Last bid of a settled auction, nothing references the escrow anymore
+        if escrow_account.is_completed && auction.bid_count == 0 {
+            escrow_account.close(ctx.accounts.initializer.to_account_info())?;
+        }
+
+        Ok(())
+    }
+
+    /// Lock tokens in a hash time-locked escrow
+    /// The counterparty claims them with the preimage of `hashlock` before `timelock`,
+    /// after that Alice can only get them back through `cancel`
+    pub fn initialize_htlc(
+        ctx: Context<InitializeEscrow>,
+        offer_id: u64,            // Caller-chosen id, shares the escrow id space
+        amount_to_send: u64,      // Amount of DED tokens locked for the counterparty
+        counterparty: Pubkey,     // Only wallet that can claim the tokens
+        hashlock: [u8; 32],       // SHA-256 hash of the secret preimage
+        timelock: i64,            // Unix timestamp after which Alice can refund
+    ) -> Result<()> {
+        require!(!ctx.accounts.config.paused, EscrowError::Paused);
+        require!(amount_to_send > 0, EscrowError::InvalidAmount);
+
+        let now = Clock::get()?.unix_timestamp;
+        require!(timelock > now, EscrowError::InvalidExpiry);
+
+        let mode = EscrowMode::Htlc(Htlc {
+            counterparty,
+            hashlock,
+            timelock,
+        });
+        let received = ctx.accounts.lock_tokens(offer_id, amount_to_send, 0, mode, &ctx.bumps)?;
+        let escrow_account = &ctx.accounts.escrow_account;
+
+        msg!("HTLC initialized! {} DED tokens locked until {}", received, timelock);
+
+        emit_cpi!(HtlcInitialized {
+            escrow: escrow_account.key(),
+            initializer: escrow_account.initializer,
+            offer_id,
+            mint: escrow_account.mint,
+            amount_to_send: received,
+            counterparty,
+            hashlock,
+            timelock,
+        });
+
+        Ok(())
+    }
+
+    /// Claim a hash time-locked escrow with the preimage
+    /// The preimage is emitted so the other leg of the swap can be claimed with it.
+    /// Not blocked by the pause switch, the claim window can't be extended.
+    pub fn claim_htlc(ctx: Context<ClaimHtlc>, preimage: [u8; 32]) -> Result<()> {
+        let escrow_account = &ctx.accounts.escrow_account;
+
+        require!(!escrow_account.is_completed, EscrowError::AlreadyCompleted);
+        let EscrowMode::Htlc(htlc) = escrow_account.mode else {
+            return err!(EscrowError::InvalidEscrowMode);
+        };
+
+        require_keys_eq!(
+            ctx.accounts.counterparty.key(),
+            htlc.counterparty,
+            EscrowError::TakerNotAllowed
+        );
+        let now = Clock::get()?.unix_timestamp;
+        require!(now < htlc.timelock, EscrowError::Expired);
+        require!(
+            hash(&preimage).to_bytes() == htlc.hashlock,
+            EscrowError::InvalidPreimage
+        );
+
+        // Transfer the locked tokens to the counterparty
+        drain_and_close_vault(
+            escrow_account,
+            &ctx.accounts.vault,
+            &ctx.accounts.mint,
+            ctx.accounts.counterparty_token_account.to_account_info(),
+            ctx.accounts.initializer.to_account_info(),
+            &ctx.accounts.token_program,
+        )?;
+
+        msg!("HTLC claimed! Preimage {:?}", preimage);
+
+        emit_cpi!(HtlcClaimed {
+            escrow: escrow_account.key(),
+            initializer: escrow_account.initializer,
+            counterparty: htlc.counterparty,
+            mint: escrow_account.mint,
+            amount: ctx.accounts.vault.amount,
+            hashlock: htlc.hashlock,
+            preimage,
+        });
+
+        Ok(())
+    }
+
+    /// Raise a dispute on an arbitrated escrow
+    /// Either Alice or the allowed taker can do this, it freezes the escrow
+    /// until the arbiter resolves it
+    pub fn raise_dispute(ctx: Context<RaiseDispute>) -> Result<()> {
+        let escrow_account = &mut ctx.accounts.escrow_account;
+        let party = ctx.accounts.party.key();
+
+        require!(!escrow_account.is_completed, EscrowError::AlreadyCompleted);
+        require!(escrow_account.arbiter.is_some(), EscrowError::NoArbiter);
+        require!(!escrow_account.disputed, EscrowError::Disputed);
+        require!(
+            party == escrow_account.initializer || escrow_account.allowed_taker == Some(party),
+            EscrowError::Unauthorized
+        );
+
+        escrow_account.disputed = true;
+
+        msg!("Dispute raised by {}", party);
+
+        emit_cpi!(DisputeRaised {
+            escrow: escrow_account.key(),
+            raised_by: party,
+        });
+
+        Ok(())
+    }
+
+    /// Resolve a dispute
+    /// The arbiter sends what is left in the vault to the taker, back to Alice,
+    /// or splits it between them. Closes the escrow either way.
+    pub fn resolve_dispute(ctx: Context<ResolveDispute>, resolution: DisputeResolution) -> Result<()> {
+        let escrow_account = &ctx.accounts.escrow_account;
+
+        require!(escrow_account.disputed, EscrowError::NotDisputed);
+
+        let taker_bps = resolution.taker_bps();
+        require!(taker_bps as u64 <= BPS_DENOMINATOR, EscrowError::InvalidResolution);
+
+        // Rounds down in Alice's favor
+        let vault_amount = ctx.accounts.vault.amount;
+        let taker_amount = (vault_amount as u128 * taker_bps as u128 / BPS_DENOMINATOR as u128) as u64;
+        let initializer_amount = vault_amount - taker_amount;
+
+        for (destination, amount) in [
+            (ctx.accounts.taker_token_account.to_account_info(), taker_amount),
+            (ctx.accounts.initializer_token_account.to_account_info(), initializer_amount),
+        ] {
+            if amount == 0 {
+                continue;
+            }
+
+            transfer_from_vault(
+                escrow_account,
+                &ctx.accounts.vault,
+                &ctx.accounts.mint,
+                destination,
+                &ctx.accounts.token_program,
+                amount,
+            )?;
+        }
+
+        close_vault(
+            escrow_account,
+            &ctx.accounts.vault,
+            &ctx.accounts.mint,
+            ctx.accounts.initializer.to_account_info(),
+            &ctx.accounts.token_program,
+        )?;
+
+        msg!(
+            "Dispute resolved! {} tokens to the taker, {} back to the initializer",
+            taker_amount,
+            initializer_amount
+        );
+
+        emit_cpi!(DisputeResolved {
+            escrow: escrow_account.key(),
+            arbiter: ctx.accounts.arbiter.key(),
+            taker_bps,
+            taker_amount,
+            initializer_amount,
+        });
+
+        Ok(())
+    }
+
+    /// Lock tokens behind an M-of-N approval
+    /// The tokens only go to `beneficiary`, or back to Alice, once `threshold`
+    /// of the approvers have approved that outcome
+    pub fn initialize_multisig_escrow(
+        ctx: Context<InitializeEscrow>,
+        offer_id: u64,            // Caller-chosen id, shares the escrow id space
+        amount_to_send: u64,      // Amount of DED tokens locked
+        beneficiary: Pubkey,      // Wallet receiving the tokens on release
+        approvers: Vec<Pubkey>,   // Wallets allowed to approve, at most `MAX_APPROVERS`
+        threshold: u8,            // Approvals needed to release or refund
+    ) -> Result<()> {
+        require!(!ctx.accounts.config.paused, EscrowError::Paused);
+        require!(amount_to_send > 0, EscrowError::InvalidAmount);
+        require!(
+            approvers.len() <= MAX_APPROVERS
+                && threshold > 0
+                && threshold as usize <= approvers.len()
+                && approvers
+                    .iter()
+                    .enumerate()
+                    .all(|(index, approver)| !approvers[..index].contains(approver)),
+            EscrowError::InvalidApprovers
+        );
+
+        let mode = EscrowMode::Multisig(Multisig {
+            beneficiary,
+            approvers: approvers.clone(),
+            threshold,
+            release_approvals: 0,
+            refund_approvals: 0,
+        });
+        let received = ctx.accounts.lock_tokens(offer_id, amount_to_send, 0, mode, &ctx.bumps)?;
+        let escrow_account = &ctx.accounts.escrow_account;
+
+        msg!(
+            "Multisig escrow initialized! {} DED tokens locked, {} of {} approvals needed",
+            received,
+            threshold,
+            approvers.len()
+        );
+
+        emit_cpi!(MultisigEscrowInitialized {
+            escrow: escrow_account.key(),
+            initializer: escrow_account.initializer,
+            offer_id,
+            mint: escrow_account.mint,
+            amount_to_send: received,
+            beneficiary,
+            approvers,
+            threshold,
+        });
+
+        Ok(())
+    }
+
+    /// Approve releasing or refunding a multisig escrow
+    /// Approvals are recorded on the escrow, so they can be gathered across transactions.
+    /// Each approver backs one action at a time, approving one withdraws their approval
+    /// of the other, so nobody counts toward both thresholds.
+    pub fn approve(ctx: Context<Approve>, action: MultisigAction) -> Result<()> {
+        let escrow_account = &mut ctx.accounts.escrow_account;
+        let approver = ctx.accounts.approver.key();
+
+        require!(!escrow_account.is_completed, EscrowError::AlreadyCompleted);
+        let EscrowMode::Multisig(multisig) = &mut escrow_account.mode else {
+            return err!(EscrowError::InvalidEscrowMode);
+        };
+
+        let index = multisig
+            .approvers
+            .iter()
+            .position(|key| *key == approver)
+            .ok_or(EscrowError::Unauthorized)?;
+        let (approvals, other_approvals) = match action {
+            MultisigAction::Release => (&mut multisig.release_approvals, &mut multisig.refund_approvals),
+            MultisigAction::Refund => (&mut multisig.refund_approvals, &mut multisig.release_approvals),
+        };
+        require!(*approvals & (1 << index) == 0, EscrowError::AlreadyApproved);
+        *approvals |= 1 << index;
+        *other_approvals &= !(1 << index);
+        let approval_count = approvals.count_ones() as u8;
+
+        msg!("Approved by {}, {} of {}", approver, approval_count, multisig.threshold);
+
+        emit_cpi!(MultisigApproved {
+            escrow: escrow_account.key(),
+            approver,
+            action,
+            approval_count,
+        });
+
+        Ok(())
+    }
+
+    /// Carry out an approved multisig release or refund
+    /// Permissionless once the action has reached its threshold.
+    /// Sends the vault to the beneficiary (release) or back to Alice (refund).
+    pub fn execute_multisig(ctx: Context<ExecuteMultisig>, action: MultisigAction) -> Result<()> {
+        let escrow_account = &ctx.accounts.escrow_account;
+
+        require!(!escrow_account.is_completed, EscrowError::AlreadyCompleted);
+        let EscrowMode::Multisig(multisig) = &escrow_account.mode else {
+            return err!(EscrowError::InvalidEscrowMode);
+        };
+
+        let approvals = match action {
+            MultisigAction::Release => multisig.release_approvals,
+            MultisigAction::Refund => multisig.refund_approvals,
+        };
+        require!(
+            approvals.count_ones() >= multisig.threshold as u32,
+            EscrowError::ThresholdNotReached
+        );
+
+        let recipient_token_account = &ctx.accounts.recipient_token_account;
+        match action {
+            MultisigAction::Release => require_keys_eq!(
+                recipient_token_account.owner,
+                multisig.beneficiary,
+                EscrowError::Unauthorized
+            ),
+            MultisigAction::Refund => require_keys_eq!(
+                recipient_token_account.key(),
+                escrow_account.initializer_token_account,
+                EscrowError::Unauthorized
+            ),
+        }
+
+        // Transfer the locked tokens to the approved recipient
+        drain_and_close_vault(
+            escrow_account,
+            &ctx.accounts.vault,
+            &ctx.accounts.mint,
+            recipient_token_account.to_account_info(),
+            ctx.accounts.initializer.to_account_info(),
+            &ctx.accounts.token_program,
+        )?;
+
+        msg!("Multisig escrow executed! {} tokens sent", ctx.accounts.vault.amount);
+
+        emit_cpi!(MultisigExecuted {
+            escrow: escrow_account.key(),
+            action,
+            recipient_token_account: recipient_token_account.key(),
+            amount: ctx.accounts.vault.amount,
+        });
+
+        Ok(())
+    }
+
+    /// Lock tokens to be paid out milestone by milestone
+    /// Locks the sum of the milestone amounts. Alice (or the approver) releases each milestone
+    /// to the beneficiary on its own, `cancel` refunds whatever hasn't been released yet.
+    pub fn initialize_milestone_escrow(
+        ctx: Context<InitializeEscrow>,
+        offer_id: u64,             // Caller-chosen id, shares the escrow id space
+        beneficiary: Pubkey,       // Wallet receiving the released milestones
+        approver: Option<Pubkey>,  // Optional wallet that can release milestones besides Alice
+        milestones: Vec<Milestone>, // Amounts and description hashes, in order
+    ) -> Result<()> {
+        require!(!ctx.accounts.config.paused, EscrowError::Paused);
+        require!(
+            !milestones.is_empty()
+                && milestones.len() <= MAX_MILESTONES
+                && milestones.iter().all(|milestone| milestone.amount > 0),
+            EscrowError::InvalidMilestones
+        );
+
+        let total = milestones
+            .iter()
+            .try_fold(0u64, |total, milestone| total.checked_add(milestone.amount))
+            .ok_or(EscrowError::MathOverflow)?;
+
+        // Every milestone has to be fully funded, so send enough to cover the transfer fee
+        let amount_to_send = amount_with_transfer_fee(&ctx.accounts.mint, total)?;
+
+        let mode = EscrowMode::Milestones(MilestonePlan {
+            beneficiary,
+            approver,
+            milestones: milestones.clone(),
+            released: 0,
+        });
+        let received = ctx.accounts.lock_tokens(offer_id, amount_to_send, 0, mode, &ctx.bumps)?;
+        require!(received >= total, EscrowError::InvalidAmount);
+        let escrow_account = &ctx.accounts.escrow_account;
+
+        msg!(
+            "Milestone escrow initialized! {} DED tokens locked over {} milestones",
+            received,
+            milestones.len()
+        );
+
+        emit_cpi!(MilestoneEscrowInitialized {
+            escrow: escrow_account.key(),
+            initializer: escrow_account.initializer,
+            offer_id,
+            mint: escrow_account.mint,
+            amount_to_send: received,
+            beneficiary,
+            approver,
+            milestones,
+        });
+
+        Ok(())
+    }
+
+    /// Release one milestone to the beneficiary
+    /// Alice or the approver can do this. Releasing the last milestone
+    /// closes the vault and escrow account, rent goes back to Alice.
+    pub fn release_milestone(ctx: Context<ReleaseMilestone>, index: u8) -> Result<()> {
+        let escrow_account = &ctx.accounts.escrow_account;
+        let authority = ctx.accounts.authority.key();
+
+        require!(!escrow_account.is_completed, EscrowError::AlreadyCompleted);
+        let EscrowMode::Milestones(plan) = &escrow_account.mode else {
+            return err!(EscrowError::InvalidEscrowMode);
+        };
+
+        require!(
+            authority == escrow_account.initializer || plan.approver == Some(authority),
+            EscrowError::Unauthorized
+        );
+        require_keys_eq!(
+            ctx.accounts.beneficiary_token_account.owner,
+            plan.beneficiary,
+            EscrowError::Unauthorized
+        );
+
+        let milestone = plan.milestones.get(index as usize).ok_or(EscrowError::MilestoneNotFound)?;
+        require!(plan.released & (1 << index) == 0, EscrowError::MilestoneAlreadyReleased);
+
+        let released = plan.released | (1 << index);
+        let is_last = released.count_ones() as usize == plan.milestones.len();
+
+        // The last milestone also takes any rounding dust left from the transfer fee top-up
+        let amount = if is_last { ctx.accounts.vault.amount } else { milestone.amount };
+
+        // Transfer the milestone's tokens to the beneficiary
+        transfer_from_vault(
+            escrow_account,
+            &ctx.accounts.vault,
+            &ctx.accounts.mint,
+            ctx.accounts.beneficiary_token_account.to_account_info(),
+            &ctx.accounts.token_program,
+            amount,
+        )?;
+
+        if is_last {
+            close_vault(
+                escrow_account,
+                &ctx.accounts.vault,
+                &ctx.accounts.mint,
+                ctx.accounts.initializer.to_account_info(),
+                &ctx.accounts.token_program,
+            )?;
+        }
+
+        let remaining_to_send = escrow_account.remaining_to_send.saturating_sub(amount);
+
+        msg!("Milestone {} released! {} DED tokens", index, amount);
+
+        emit_cpi!(MilestoneReleased {
+            escrow: escrow_account.key(),
+            released_by: authority,
+            index,
+            amount,
+            remaining_to_send,
+        });
+
+        let escrow_account = &mut ctx.accounts.escrow_account;
+        if let EscrowMode::Milestones(plan) = &mut escrow_account.mode {
+            plan.released = released;
+        }
+        escrow_account.remaining_to_send = remaining_to_send;
+
+        if is_last {
+            escrow_account.close(ctx.accounts.initializer.to_account_info())?;
+        }
+
+        Ok(())
+    }
+
+    /// Lock tokens that vest linearly to a beneficiary
+    /// Nothing can be claimed before the cliff, everything from `end_ts` on
+    #[allow(clippy::too_many_arguments)]
+    pub fn initialize_vesting(
+        ctx: Context<InitializeEscrow>,
+        offer_id: u64,            // Caller-chosen id, shares the escrow id space
+        amount_to_send: u64,      // Amount of DED tokens granted
+        beneficiary: Pubkey,      // Wallet the tokens vest to
+        start_ts: i64,            // Unix timestamp vesting starts at
+        cliff_ts: Option<i64>,    // Optional unix timestamp before which nothing is claimable
+        end_ts: i64,              // Unix timestamp everything has vested at
+        revocable: bool,          // Whether Alice can take back the unvested remainder
+    ) -> Result<()> {
+        require!(!ctx.accounts.config.paused, EscrowError::Paused);
+        require!(amount_to_send > 0, EscrowError::InvalidAmount);
+        require!(
+            end_ts > start_ts
+                && cliff_ts.is_none_or(|cliff_ts| cliff_ts >= start_ts && cliff_ts <= end_ts),
+            EscrowError::InvalidVestingSchedule
+        );
+
+        let mode = EscrowMode::Vesting(Vesting {
+            beneficiary,
+            start_ts,
+            cliff_ts,
+            end_ts,
+            revocable,
+            claimed: 0,
+            revoked_at: None,
+        });
+        let received = ctx.accounts.lock_tokens(offer_id, amount_to_send, 0, mode, &ctx.bumps)?;
+        let escrow_account = &ctx.accounts.escrow_account;
+
+        msg!("Vesting initialized! {} DED tokens vesting until {}", received, end_ts);
+
+        emit_cpi!(VestingInitialized {
+            escrow: escrow_account.key(),
+            initializer: escrow_account.initializer,
+            offer_id,
+            mint: escrow_account.mint,
+            amount_to_send: received,
+            beneficiary,
+            start_ts,
+            cliff_ts,
+            end_ts,
+            revocable,
+        });
+
+        Ok(())
+    }
+
+    /// Claim the vested tokens that haven't been claimed yet
+    /// Not blocked by the pause switch. Claiming the last of the grant
+    /// closes the vault and escrow account, rent goes back to Alice.
+    pub fn claim(ctx: Context<Claim>) -> Result<()> {
+        let escrow_account = &ctx.accounts.escrow_account;
+
+        let EscrowMode::Vesting(vesting) = &escrow_account.mode else {
+            return err!(EscrowError::InvalidEscrowMode);
+        };
+
+        require_keys_eq!(
+            ctx.accounts.beneficiary.key(),
+            vesting.beneficiary,
+            EscrowError::Unauthorized
+        );
+
+        let now = Clock::get()?.unix_timestamp;
+        let vested = vesting.vested_amount(escrow_account.amount_to_send, now);
+        let amount = vested - vesting.claimed;
+        require!(amount > 0, EscrowError::NothingToClaim);
+
+        // Transfer the newly vested tokens to the beneficiary
+        transfer_from_vault(
+            escrow_account,
+            &ctx.accounts.vault,
+            &ctx.accounts.mint,
+            ctx.accounts.beneficiary_token_account.to_account_info(),
+            &ctx.accounts.token_program,
+            amount,
+        )?;
+
+        let remaining_to_send = escrow_account.remaining_to_send - amount;
+
+        if remaining_to_send == 0 {
+            close_vault(
+                escrow_account,
+                &ctx.accounts.vault,
+                &ctx.accounts.mint,
+                ctx.accounts.initializer.to_account_info(),
+                &ctx.accounts.token_program,
+            )?;
+        }
+
+        msg!("Vested tokens claimed! {} DED tokens", amount);
+
+        emit_cpi!(VestedTokensClaimed {
+            escrow: escrow_account.key(),
+            beneficiary: vesting.beneficiary,
+            amount,
+            total_claimed: vested,
+        });
+
+        let escrow_account = &mut ctx.accounts.escrow_account;
+        if let EscrowMode::Vesting(vesting) = &mut escrow_account.mode {
+            vesting.claimed = vested;
+        }
+        escrow_account.remaining_to_send = remaining_to_send;
+
+        if remaining_to_send == 0 {
+            escrow_account.close(ctx.accounts.initializer.to_account_info())?;
+        }
+
+        Ok(())
+    }
+
+    /// Revoke a vesting grant
+    /// Alice takes back the unvested remainder, what has vested so far stays claimable
+    pub fn revoke(ctx: Context<Revoke>) -> Result<()> {
+        let escrow_account = &ctx.accounts.escrow_account;
+
+        let EscrowMode::Vesting(vesting) = &escrow_account.mode else {
+            return err!(EscrowError::InvalidEscrowMode);
+        };
+
+        require!(vesting.revocable, EscrowError::NotRevocable);
+        require!(vesting.revoked_at.is_none(), EscrowError::AlreadyRevoked);
+
+        let now = Clock::get()?.unix_timestamp;
+        let vested = vesting.vested_amount(escrow_account.amount_to_send, now);
+        let unvested = escrow_account.amount_to_send - vested;
+
+        if unvested > 0 {
+            // Return the unvested tokens to Alice
+            transfer_from_vault(
+                escrow_account,
+                &ctx.accounts.vault,
+                &ctx.accounts.mint,
+                ctx.accounts.initializer_token_account.to_account_info(),
+                &ctx.accounts.token_program,
+                unvested,
+            )?;
+        }
+
+        let remaining_to_send = escrow_account.remaining_to_send - unvested;
+
+        if remaining_to_send == 0 {
+            close_vault(
+                escrow_account,
+                &ctx.accounts.vault,
+                &ctx.accounts.mint,
+                ctx.accounts.initializer.to_account_info(),
+                &ctx.accounts.token_program,
+            )?;
+        }
+
+        msg!("Vesting revoked! {} DED tokens returned, {} vested", unvested, vested);
+
+        emit_cpi!(VestingRevoked {
+            escrow: escrow_account.key(),
+            initializer: escrow_account.initializer,
+            unvested_returned: unvested,
+            vested,
+        });
+
+        let escrow_account = &mut ctx.accounts.escrow_account;
+        if let EscrowMode::Vesting(vesting) = &mut escrow_account.mode {
+            vesting.revoked_at = Some(now);
+        }
+        escrow_account.remaining_to_send = remaining_to_send;
+
+        if remaining_to_send == 0 {
+            escrow_account.close(ctx.accounts.initializer.to_account_info())?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Upper bound for `Config::fee_bps` (10%)
+pub const MAX_FEE_BPS: u16 = 1_000;
+pub const BPS_DENOMINATOR: u64 = 10_000;
+/// Most payout recipients a single escrow can split proceeds between
+pub const MAX_PAYOUT_SPLITS: usize = 5;
+/// Most approvers a multisig escrow can have, approvals are tracked in a `u8` bitmask
+pub const MAX_APPROVERS: usize = 8;
+/// Most milestones a milestone escrow can have, releases are tracked in a `u16` bitmask
+pub const MAX_MILESTONES: usize = 16;
+
+/// Move lamports out of a system-owned signer, no-op for zero amounts
+pub fn pay_lamports<'info>(
+    from: &AccountInfo<'info>,
+    to: &AccountInfo<'info>,
+    amount: u64,
+) -> Result<()> {
+    if amount == 0 {
+        return Ok(());
+    }
+
+    let ix = anchor_lang::solana_program::system_instruction::transfer(from.key, to.key, amount);
+    anchor_lang::solana_program::program::invoke(&ix, &[from.clone(), to.clone()])?;
+
+    Ok(())
+}
+
+/// Move lamports out of an account owned by this program, no-op for zero amounts
+pub fn withdraw_lamports<'info>(
+    from: &AccountInfo<'info>,
+    to: &AccountInfo<'info>,
+    amount: u64,
+) -> Result<()> {
+    if amount == 0 {
+        return Ok(());
+    }
+
+    from.sub_lamports(amount)?;
+    to.add_lamports(amount)?;
+
+    Ok(())
+}
+
+/// Signer seeds of an escrow's vault PDA, `offer_id_bytes` is the escrow's `offer_id.to_le_bytes()`
+pub fn vault_signer_seeds<'a>(
+    escrow_account: &'a EscrowAccount,
+    offer_id_bytes: &'a [u8; 8],
+) -> [&'a [u8]; 4] {
+    [
+        b"vault",
+        escrow_account.initializer.as_ref(),
+        offer_id_bytes,
+        std::slice::from_ref(&escrow_account.vault_bump),
+    ]
+}
+
+/// Move `amount` tokens out of an escrow's vault, signed by the vault PDA
+pub fn transfer_from_vault<'info>(
+    escrow_account: &EscrowAccount,
+    vault: &InterfaceAccount<'info, TokenAccount>,
+    mint: &InterfaceAccount<'info, Mint>,
+    to: AccountInfo<'info>,
+    token_program: &Interface<'info, TokenInterface>,
+    amount: u64,
+) -> Result<()> {
+    let offer_id_bytes = escrow_account.offer_id.to_le_bytes();
+    let seeds = vault_signer_seeds(escrow_account, &offer_id_bytes);
+    let signer = &[&seeds[..]];
+
+    let cpi_accounts = TransferChecked {
+        from: vault.to_account_info(),
+        mint: mint.to_account_info(),
+        to,
+        authority: vault.to_account_info(),
+    };
+    let cpi_program = token_program.to_account_info();
+    let cpi_ctx = CpiContext::new_with_signer(cpi_program, cpi_accounts, signer);
+
+    token_interface::transfer_checked(cpi_ctx, amount, mint.decimals)
+}
+
+/// Close an escrow's empty vault, its rent goes to `destination`.
+/// Token-2022 won't close an account still holding withheld transfer fees,
+/// so for transfer-fee mints those are harvested to the (writable) mint first.
+pub fn close_vault<'info>(
+    escrow_account: &EscrowAccount,
+    vault: &InterfaceAccount<'info, TokenAccount>,
+    mint: &InterfaceAccount<'info, Mint>,
+    destination: AccountInfo<'info>,
+    token_program: &Interface<'info, TokenInterface>,
+) -> Result<()> {
+    if transfer_fee_config(mint)?.is_some() {
+        let cpi_accounts = HarvestWithheldTokensToMint {
+            token_program_id: token_program.to_account_info(),
+            mint: mint.to_account_info(),
+        };
+        let cpi_ctx = CpiContext::new(token_program.to_account_info(), cpi_accounts);
+
+        harvest_withheld_tokens_to_mint(cpi_ctx, vec![vault.to_account_info()])?;
+    }
+
+    let offer_id_bytes = escrow_account.offer_id.to_le_bytes();
+    let seeds = vault_signer_seeds(escrow_account, &offer_id_bytes);
+    let signer = &[&seeds[..]];
+
+    let cpi_accounts = CloseAccount {
+        account: vault.to_account_info(),
+        destination,
+        authority: vault.to_account_info(),
+    };
+    let cpi_program = token_program.to_account_info();
+    let cpi_ctx = CpiContext::new_with_signer(cpi_program, cpi_accounts, signer);
+
+    token_interface::close_account(cpi_ctx)
+}
+
+/// Send everything left in an escrow's vault to `to`, then close the vault.
+/// Its rent goes to `destination`, normally the initializer.
+pub fn drain_and_close_vault<'info>(
+    escrow_account: &EscrowAccount,
+    vault: &InterfaceAccount<'info, TokenAccount>,
+    mint: &InterfaceAccount<'info, Mint>,
+    to: AccountInfo<'info>,
+    destination: AccountInfo<'info>,
+    token_program: &Interface<'info, TokenInterface>,
+) -> Result<()> {
+    transfer_from_vault(escrow_account, vault, mint, to, token_program, vault.amount)?;
+    close_vault(escrow_account, vault, mint, destination, token_program)
+}
+
+/// Transfer fee config of `mint`, `None` unless it is a Token-2022 mint
+/// with the transfer fee extension
+fn transfer_fee_config(mint: &InterfaceAccount<Mint>) -> Result<Option<TransferFeeConfig>> {
+    let mint_info = mint.to_account_info();
+    if *mint_info.owner != spl_token_2022::ID {
+        return Ok(None);
+    }
+
+    let mint_data = mint_info.try_borrow_data()?;
+    let mint_state = StateWithExtensions::<spl_token_2022::state::Mint>::unpack(&mint_data)?;
+
+    Ok(mint_state.get_extension::<TransferFeeConfig>().ok().copied())
+}
+
+/// Fee withheld when moving `amount` of `mint`
+pub fn transfer_fee(mint: &InterfaceAccount<Mint>, amount: u64) -> Result<u64> {
+    let Some(fee_config) = transfer_fee_config(mint)? else {
+        return Ok(0);
+    };
+
+    fee_config
+        .calculate_epoch_fee(Clock::get()?.epoch, amount)
+        .ok_or_else(|| error!(EscrowError::MathOverflow))
+}
+
+/// Amount to send so that `net_amount` of `mint` arrives after the transfer fee
+pub fn amount_with_transfer_fee(mint: &InterfaceAccount<Mint>, net_amount: u64) -> Result<u64> {
+    let Some(fee_config) = transfer_fee_config(mint)? else {
+        return Ok(net_amount);
+    };
+
+    fee_config
+        .calculate_inverse_epoch_fee(Clock::get()?.epoch, net_amount)
+        .and_then(|fee| net_amount.checked_add(fee))
+        .ok_or_else(|| error!(EscrowError::MathOverflow))
+}
+
+#[event_cpi]
+#[derive(Accounts)]
+pub struct InitializeConfig<'info> {
+    #[account(mut)]
+    pub admin: Signer<'info>,
+
+    #[account(
+        init,
+        payer = admin,
+        space = 8 + Config::INIT_SPACE,
+        seeds = [b"config"],
+        bump
+    )]
+    pub config: Account<'info, Config>,
+
+    /// This program, used to look up its program data account
+    #[account(constraint = escrow_program.programdata_address()? == Some(program_data.key()))]
+    pub escrow_program: Program<'info, program::TokenEscrow>,
+
+    #[account(constraint = program_data.upgrade_authority_address == Some(admin.key()))]
+    pub program_data: Account<'info, ProgramData>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[event_cpi]
+#[derive(Accounts)]
+pub struct UpdateConfig<'info> {
+    pub admin: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"config"],
+        bump = config.bump,
+        has_one = admin,
+    )]
+    pub config: Account<'info, Config>,
+}
+
+#[event_cpi]
+#[derive(Accounts)]
+pub struct AcceptAdmin<'info> {
+    pub new_admin: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"config"],
+        bump = config.bump,
+        constraint = config.pending_admin == Some(new_admin.key()) @ EscrowError::Unauthorized,
+    )]
+    pub config: Account<'info, Config>,
+}
+
+#[event_cpi]
+#[derive(Accounts)]
+#[instruction(offer_id: u64)]
+pub struct InitializeEscrow<'info> {
+    #[account(mut)]
+    pub initializer: Signer<'info>,
+
+    #[account(mint::token_program = token_program)]
+    pub mint: InterfaceAccount<'info, Mint>,
+
+    #[account(
+        mut,
+        constraint = initializer_token_account.owner == initializer.key(),
+        constraint = initializer_token_account.mint == mint.key()
+    )]
+    pub initializer_token_account: InterfaceAccount<'info, TokenAccount>,
+
+    /// Mint the taker pays in, leave empty to be paid in SOL
+    pub payment_mint: Option<InterfaceAccount<'info, Mint>>,
+
+    #[account(
+        init,
+        payer = initializer,
+        space = 8 + EscrowAccount::BASE_SPACE,
+        seeds = [b"escrow", initializer.key().as_ref(), offer_id.to_le_bytes().as_ref()],
+        bump
+    )]
+    pub escrow_account: Account<'info, EscrowAccount>,
+
+    #[account(
+        init,
+        payer = initializer,
+        seeds = [b"vault", initializer.key().as_ref(), offer_id.to_le_bytes().as_ref()],
+        bump,
+        token::mint = mint,
+        token::authority = vault,
+        token::token_program = token_program,
+    )]
+    pub vault: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(seeds = [b"config"], bump = config.bump)]
+    pub config: Account<'info, Config>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+    pub system_program: Program<'info, System>,
+}
+
+impl<'info> InitializeEscrow<'info> {
+    /// Record a new escrow and lock Alice's tokens in the vault.
+    /// Offer-only terms (expiry, taker, payouts, Dutch auction, arbiter) are left
+    /// unset, `initialize_escrow` fills them in afterwards.
+    /// Returns the amount that actually landed in the vault.
+    fn lock_tokens(
+        &mut self,
+        offer_id: u64,
+        amount_to_send: u64,
+        amount_to_receive: u64,
+        mode: EscrowMode,
+        bumps: &InitializeEscrowBumps,
+    ) -> Result<u64> {
+        let escrow_account = &mut self.escrow_account;
+
+        escrow_account.initializer = self.initializer.key();
+        escrow_account.offer_id = offer_id;
+        escrow_account.initializer_token_account = self.initializer_token_account.key();
+        escrow_account.amount_to_receive = amount_to_receive;
+        escrow_account.mint = self.mint.key();
+        escrow_account.payment_mint = self.payment_mint.as_ref().map(|mint| mint.key());
+        escrow_account.escrow_bump = bumps.escrow_account;
+        escrow_account.vault_bump = bumps.vault;
+        escrow_account.is_completed = false;
+        escrow_account.expires_at = None;
+        escrow_account.allowed_taker = None;
+        escrow_account.payouts = Vec::new();
+        escrow_account.dutch_auction = None;
+        escrow_account.mode = mode;
+        escrow_account.arbiter = None;
+        escrow_account.disputed = false;
+        self.fit_escrow_account()?;
+
+        // Transfer tokens from Alice to escrow vault
+        let cpi_accounts = TransferChecked {
+            from: self.initializer_token_account.to_account_info(),
+            mint: self.mint.to_account_info(),
+            to: self.vault.to_account_info(),
+            authority: self.initializer.to_account_info(),
+        };
+        let cpi_program = self.token_program.to_account_info();
+        let cpi_ctx = CpiContext::new(cpi_program, cpi_accounts);
+
+        token_interface::transfer_checked(cpi_ctx, amount_to_send, self.mint.decimals)?;
+
+        // Book what actually landed in the vault, transfer-fee mints deliver less than sent
+        self.vault.reload()?;
+        let received = self.vault.amount;
+        require!(received > 0, EscrowError::InvalidAmount);
+
+        self.escrow_account.amount_to_send = received;
+        self.escrow_account.remaining_to_send = received;
+
+        Ok(received)
+    }
+
+    /// Grow the escrow account to fit its payout splits and mode, Alice pays the extra rent
+    fn fit_escrow_account(&self) -> Result<()> {
+        let escrow_info = self.escrow_account.to_account_info();
+        let space = 8 + self.escrow_account.space();
+        if space <= escrow_info.data_len() {
+            return Ok(());
+        }
+
+        let rent = Rent::get()?
+            .minimum_balance(space)
+            .saturating_sub(escrow_info.lamports());
+        pay_lamports(&self.initializer.to_account_info(), &escrow_info, rent)?;
+        escrow_info.resize(space)?;
+
+        Ok(())
+    }
+}
+
+#[event_cpi]
+#[derive(Accounts)]
+pub struct Exchange<'info> {
+    #[account(mut)]
+    pub taker: Signer<'info>,
+
+    /// CHECK: This is the initializer who will receive SOL
+    #[account(mut)]
+    pub initializer: UncheckedAccount<'info>,
+
+    #[account(
+        mut,
+        constraint = taker_token_account.owner == taker.key(),
+        constraint = taker_token_account.mint == escrow_account.mint
+    )]
+    pub taker_token_account: InterfaceAccount<'info, TokenAccount>,
+
+    /// Only needed when the escrow is paid in a token instead of SOL
+    /// Needed when the escrow is paid in a token, or when Bob pays a SOL escrow in wSOL
+    #[account(
+        mut,
+        constraint = taker_payment_account.owner == taker.key(),
+        constraint = taker_payment_account.mint == escrow_account.payment_mint_or_native()
+            @ EscrowError::PaymentMintMismatch
+    )]
+    pub taker_payment_account: Option<InterfaceAccount<'info, TokenAccount>>,
+
+    /// Only needed when the escrow is paid in a token instead of SOL
+    #[account(
+        mut,
+        constraint = initializer_payment_account.owner == initializer.key(),
+        constraint = Some(initializer_payment_account.mint) == escrow_account.payment_mint
+            @ EscrowError::PaymentMintMismatch
+    )]
+    pub initializer_payment_account: Option<InterfaceAccount<'info, TokenAccount>>,
+
+    /// Needed when the escrow is paid in a token, or when Bob pays a SOL escrow in wSOL
+    #[account(
+        constraint = payment_mint.key() == escrow_account.payment_mint_or_native()
+            @ EscrowError::PaymentMintMismatch
+    )]
+    pub payment_mint: Option<InterfaceAccount<'info, Mint>>,
+
+    /// Token program owning the payment mint, may differ from `token_program`
+    pub payment_token_program: Option<Interface<'info, TokenInterface>>,
+
+    /// CHECK: Temporary wSOL account for unwrapping a wSOL payment, created and closed within `exchange`
+    #[account(
+        mut,
+        seeds = [b"unwrap", escrow_account.key().as_ref()],
+        bump
+    )]
+    pub unwrap_account: Option<UncheckedAccount<'info>>,
+
+    #[account(seeds = [b"config"], bump = config.bump)]
+    pub config: Account<'info, Config>,
+
+    /// CHECK: Protocol fee receiver for SOL payments, checked against the config
+    #[account(mut, address = config.treasury)]
+    pub treasury: UncheckedAccount<'info>,
+
+    /// Only needed when the escrow is paid in a token and a fee is charged
+    #[account(
+        mut,
+        constraint = treasury_payment_account.owner == config.treasury,
+        constraint = Some(treasury_payment_account.mint) == escrow_account.payment_mint
+            @ EscrowError::PaymentMintMismatch
+    )]
+    pub treasury_payment_account: Option<InterfaceAccount<'info, TokenAccount>>,
+
+    #[account(
+        mut,
+        seeds = [
+            b"vault",
+            escrow_account.initializer.as_ref(),
+            escrow_account.offer_id.to_le_bytes().as_ref()
+        ],
+        bump = escrow_account.vault_bump,
+    )]
+    pub vault: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        seeds = [
+            b"escrow",
+            escrow_account.initializer.as_ref(),
+            escrow_account.offer_id.to_le_bytes().as_ref()
+        ],
+        bump = escrow_account.escrow_bump,
+        has_one = initializer,
+        has_one = mint,
+        constraint = escrow_account.allowed_taker.is_none_or(|allowed| allowed == taker.key())
+            @ EscrowError::TakerNotAllowed,
+    )]
+    pub escrow_account: Account<'info, EscrowAccount>,
+
+    #[account(mut)]
+    pub mint: InterfaceAccount<'info, Mint>,
+    pub token_program: Interface<'info, TokenInterface>,
+    pub system_program: Program<'info, System>,
+}
+
+impl<'info> Exchange<'info> {
+    /// Pull `amount` wSOL from the taker and unwrap it into the escrow PDA's lamports
+    /// through a temporary token account. Returns the lamports on top of `amount`
+    /// (the temporary account's rent), which are owed back to the taker.
+    fn unwrap_wsol_payment(&self, amount: u64, unwrap_bump: u8) -> Result<u64> {
+        let (
+            Some(taker_payment_account),
+            Some(payment_mint),
+            Some(payment_token_program),
+            Some(unwrap_account),
+        ) = (
+            &self.taker_payment_account,
+            &self.payment_mint,
+            &self.payment_token_program,
+            &self.unwrap_account,
+        )
+        else {
+            return err!(EscrowError::MissingPaymentAccount);
+        };
+
+        let escrow_key = self.escrow_account.key();
+        let seeds = &[b"unwrap", escrow_key.as_ref(), &[unwrap_bump]];
+        let signer = &[&seeds[..]];
+
+        // Create the temporary wSOL account, Bob fronts its rent.
+        // Someone may have sent lamports to the address already, then top up instead.
+        let rent = Rent::get()?.minimum_balance(anchor_spl::token::TokenAccount::LEN);
+        let current_lamports = unwrap_account.lamports();
+        let system_program = self.system_program.to_account_info();
+
+        if current_lamports == 0 {
+            let cpi_accounts = CreateAccount {
+                from: self.taker.to_account_info(),
+                to: unwrap_account.to_account_info(),
+            };
+            let cpi_ctx = CpiContext::new_with_signer(system_program, cpi_accounts, signer);
+
+            system_program::create_account(
+                cpi_ctx,
+                rent,
+                anchor_spl::token::TokenAccount::LEN as u64,
+                payment_token_program.key,
+            )?;
+        } else {
+            pay_lamports(
+                &self.taker.to_account_info(),
+                &unwrap_account.to_account_info(),
+                rent.saturating_sub(current_lamports),
+            )?;
+
+            let cpi_accounts = Allocate {
+                account_to_allocate: unwrap_account.to_account_info(),
+            };
+            let cpi_ctx = CpiContext::new_with_signer(system_program.clone(), cpi_accounts, signer);
+
+            system_program::allocate(cpi_ctx, anchor_spl::token::TokenAccount::LEN as u64)?;
+
+            let cpi_accounts = Assign {
+                account_to_assign: unwrap_account.to_account_info(),
+            };
+            let cpi_ctx = CpiContext::new_with_signer(system_program, cpi_accounts, signer);
+
+            system_program::assign(cpi_ctx, payment_token_program.key)?;
+        }
+
+        let deposit = unwrap_account.lamports();
+
+        let cpi_accounts = InitializeAccount3 {
+            account: unwrap_account.to_account_info(),
+            mint: payment_mint.to_account_info(),
+            authority: unwrap_account.to_account_info(),
+        };
+        let cpi_program = payment_token_program.to_account_info();
+        let cpi_ctx = CpiContext::new(cpi_program, cpi_accounts);
+
+        token_interface::initialize_account3(cpi_ctx)?;
+
+        // Move Bob's wSOL in
+        let cpi_accounts = TransferChecked {
+            from: taker_payment_account.to_account_info(),
+            mint: payment_mint.to_account_info(),
+            to: unwrap_account.to_account_info(),
+            authority: self.taker.to_account_info(),
+        };
+        let cpi_program = payment_token_program.to_account_info();
+        let cpi_ctx = CpiContext::new(cpi_program, cpi_accounts);
+
+        token_interface::transfer_checked(cpi_ctx, amount, payment_mint.decimals)?;
+
+        // Close it into the escrow PDA, which then holds `amount` plus the deposit in lamports
+        let cpi_accounts = CloseAccount {
+            account: unwrap_account.to_account_info(),
+            destination: self.escrow_account.to_account_info(),
+            authority: unwrap_account.to_account_info(),
+        };
+        let cpi_program = payment_token_program.to_account_info();
+        let cpi_ctx = CpiContext::new_with_signer(cpi_program, cpi_accounts, signer);
+
+        token_interface::close_account(cpi_ctx)?;
+
+        Ok(deposit)
+    }
+}
+
+#[event_cpi]
+#[derive(Accounts)]
+pub struct UpdateEscrow<'info> {
+    pub initializer: Signer<'info>,
+
+    #[account(
+        mut,
+        constraint = initializer_token_account.owner == initializer.key(),
+        constraint = initializer_token_account.mint == escrow_account.mint
+    )]
+    pub initializer_token_account: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        seeds = [
+            b"vault",
+            escrow_account.initializer.as_ref(),
+            escrow_account.offer_id.to_le_bytes().as_ref()
+        ],
+        bump = escrow_account.vault_bump,
+    )]
+    pub vault: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        seeds = [
+            b"escrow",
+            initializer.key().as_ref(),
+            escrow_account.offer_id.to_le_bytes().as_ref()
+        ],
+        bump = escrow_account.escrow_bump,
+        has_one = initializer,
+        has_one = mint,
+    )]
+    pub escrow_account: Account<'info, EscrowAccount>,
+
+    pub mint: InterfaceAccount<'info, Mint>,
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[event_cpi]
+#[derive(Accounts)]
+pub struct Cancel<'info> {
+    #[account(mut)]
+    pub initializer: Signer<'info>,
+
+    #[account(
+        mut,
+        constraint = initializer_token_account.owner == initializer.key(),
+        constraint = initializer_token_account.mint == escrow_account.mint
+    )]
+    pub initializer_token_account: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        seeds = [
+            b"vault",
+            escrow_account.initializer.as_ref(),
+            escrow_account.offer_id.to_le_bytes().as_ref()
+        ],
+        bump = escrow_account.vault_bump,
+    )]
+    pub vault: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        seeds = [
+            b"escrow",
+            initializer.key().as_ref(),
+            escrow_account.offer_id.to_le_bytes().as_ref()
+        ],
+        bump = escrow_account.escrow_bump,
+        has_one = initializer,
+        has_one = mint,
+        close = initializer
+    )]
+    pub escrow_account: Account<'info, EscrowAccount>,
+
+    #[account(mut)]
+    pub mint: InterfaceAccount<'info, Mint>,
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[event_cpi]
+#[derive(Accounts)]
+pub struct CloseCompleted<'info> {
+    #[account(mut)]
+    pub initializer: Signer<'info>,
+
+    #[account(mut, seeds = [b"vault", initializer.key().as_ref()], bump)]
+    pub vault: InterfaceAccount<'info, TokenAccount>,
+
+    /// CHECK: Legacy escrow, loaded by `LegacyEscrowAccount::load`
+    #[account(mut, seeds = [b"escrow", initializer.key().as_ref()], bump)]
+    pub escrow_account: UncheckedAccount<'info>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[event_cpi]
+#[derive(Accounts)]
+pub struct CancelLegacy<'info> {
+    #[account(mut)]
+    pub initializer: Signer<'info>,
+
+    #[account(mut)]
+    pub initializer_token_account: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(mut, seeds = [b"vault", initializer.key().as_ref()], bump)]
+    pub vault: InterfaceAccount<'info, TokenAccount>,
+
+    /// CHECK: Legacy escrow, loaded by `LegacyEscrowAccount::load`
+    #[account(mut, seeds = [b"escrow", initializer.key().as_ref()], bump)]
+    pub escrow_account: UncheckedAccount<'info>,
+
+    pub mint: InterfaceAccount<'info, Mint>,
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[event_cpi]
+#[derive(Accounts)]
+pub struct ReclaimExpired<'info> {
+    pub caller: Signer<'info>,
+
+    /// CHECK: This is the initializer who gets the tokens and rent back
+    #[account(mut)]
+    pub initializer: UncheckedAccount<'info>,
+
+    #[account(mut)]
+    pub initializer_token_account: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        seeds = [
+            b"vault",
+            escrow_account.initializer.as_ref(),
+            escrow_account.offer_id.to_le_bytes().as_ref()
+        ],
+        bump = escrow_account.vault_bump,
+    )]
+    pub vault: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        seeds = [
+            b"escrow",
+            escrow_account.initializer.as_ref(),
+            escrow_account.offer_id.to_le_bytes().as_ref()
+        ],
+        bump = escrow_account.escrow_bump,
+        has_one = initializer,
+        has_one = initializer_token_account,
+        has_one = mint,
+        close = initializer
+    )]
+    pub escrow_account: Account<'info, EscrowAccount>,
+
+    #[account(mut)]
+    pub mint: InterfaceAccount<'info, Mint>,
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[event_cpi]
+#[derive(Accounts)]
+#[instruction(offer_id: u64)]
+pub struct InitializeBid<'info> {
+    #[account(mut)]
+    pub buyer: Signer<'info>,
+
+    #[account(mint::token_program = token_program)]
+    pub mint: InterfaceAccount<'info, Mint>,
+
+    #[account(
+        constraint = buyer_token_account.owner == buyer.key(),
+        constraint = buyer_token_account.mint == mint.key()
+    )]
+    pub buyer_token_account: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        init,
+        payer = buyer,
+        space = 8 + BidEscrow::INIT_SPACE,
+        seeds = [b"bid_escrow", buyer.key().as_ref(), offer_id.to_le_bytes().as_ref()],
+        bump
+    )]
+    pub bid_escrow: Account<'info, BidEscrow>,
+
+    #[account(seeds = [b"config"], bump = config.bump)]
+    pub config: Account<'info, Config>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+    pub system_program: Program<'info, System>,
+}
+
+#[event_cpi]
+#[derive(Accounts)]
+pub struct FillBid<'info> {
+    #[account(mut)]
+    pub seller: Signer<'info>,
+
+    /// CHECK: This is the buyer who gets the bid PDA rent back
+    #[account(mut)]
+    pub buyer: UncheckedAccount<'info>,
+
+    #[account(
+        mut,
+        constraint = seller_token_account.owner == seller.key(),
+        constraint = seller_token_account.mint == bid_escrow.mint
+    )]
+    pub seller_token_account: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(mut)]
+    pub buyer_token_account: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        seeds = [
+            b"bid_escrow",
+            bid_escrow.buyer.as_ref(),
+            bid_escrow.offer_id.to_le_bytes().as_ref()
+        ],
+        bump = bid_escrow.bump,
+        has_one = buyer,
+        has_one = buyer_token_account,
+        has_one = mint,
+        close = buyer
+    )]
+    pub bid_escrow: Account<'info, BidEscrow>,
+
+    pub mint: InterfaceAccount<'info, Mint>,
+
+    #[account(seeds = [b"config"], bump = config.bump)]
+    pub config: Account<'info, Config>,
+
+    /// CHECK: Protocol fee receiver, checked against the config
+    #[account(mut, address = config.treasury)]
+    pub treasury: UncheckedAccount<'info>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[event_cpi]
+#[derive(Accounts)]
+pub struct CancelBid<'info> {
+    #[account(mut)]
+    pub buyer: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [
+            b"bid_escrow",
+            buyer.key().as_ref(),
+            bid_escrow.offer_id.to_le_bytes().as_ref()
+        ],
+        bump = bid_escrow.bump,
+        has_one = buyer,
+        close = buyer
+    )]
+    pub bid_escrow: Account<'info, BidEscrow>,
+}
+
+#[derive(Accounts)]
+pub struct CurrentPrice<'info> {
+    #[account(has_one = mint)]
+    pub escrow_account: Account<'info, EscrowAccount>,
+
+    pub mint: InterfaceAccount<'info, Mint>,
+}
+
+#[event_cpi]
+#[derive(Accounts)]
+pub struct PlaceBid<'info> {
+    #[account(mut)]
+    pub bidder: Signer<'info>,
+
+    /// Already exists when the highest bidder raises their own bid
+    #[account(
+        init_if_needed,
+        payer = bidder,
+        space = 8 + AuctionBid::INIT_SPACE,
+        seeds = [b"auction_bid", escrow_account.key().as_ref(), bidder.key().as_ref()],
+        bump
+    )]
+    pub auction_bid: Account<'info, AuctionBid>,
+
+    /// Current highest bid, required once the auction has one unless the bidder holds it
+    #[account(
+        mut,
+        seeds = [b"auction_bid", escrow_account.key().as_ref(), previous_bid.bidder.as_ref()],
+        bump = previous_bid.bump,
+    )]
+    pub previous_bid: Option<Account<'info, AuctionBid>>,
+
+    /// CHECK: Checked against the auction's highest bidder, gets the refund
+    #[account(mut)]
+    pub previous_bidder: Option<UncheckedAccount<'info>>,
+
+    #[account(
+        mut,
+        seeds = [
+            b"escrow",
+            escrow_account.initializer.as_ref(),
+            escrow_account.offer_id.to_le_bytes().as_ref()
+        ],
+        bump = escrow_account.escrow_bump,
+    )]
+    pub escrow_account: Account<'info, EscrowAccount>,
+
+    #[account(seeds = [b"config"], bump = config.bump)]
+    pub config: Account<'info, Config>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[event_cpi]
+#[derive(Accounts)]
+pub struct SettleAuction<'info> {
+    pub caller: Signer<'info>,
+
+    /// CHECK: This is the initializer who gets the proceeds and rent back
+    #[account(mut)]
+    pub initializer: UncheckedAccount<'info>,
+
+    #[account(mut)]
+    pub initializer_token_account: InterfaceAccount<'info, TokenAccount>,
+
+    /// CHECK: Checked against the auction's highest bidder, gets the bid PDA's rent back
+    #[account(mut)]
+    pub winner: Option<UncheckedAccount<'info>>,
+
+    #[account(mut)]
+    pub winner_token_account: Option<InterfaceAccount<'info, TokenAccount>>,
+
+    #[account(
+        mut,
+        seeds = [b"auction_bid", escrow_account.key().as_ref(), winning_bid.bidder.as_ref()],
+        bump = winning_bid.bump,
+    )]
+    pub winning_bid: Option<Account<'info, AuctionBid>>,
+
+    #[account(seeds = [b"config"], bump = config.bump)]
+    pub config: Account<'info, Config>,
+
+    /// CHECK: Receives the protocol fee, checked against the config
+    #[account(mut, address = config.treasury)]
+    pub treasury: UncheckedAccount<'info>,
+
+    #[account(
+        mut,
+        seeds = [
+            b"vault",
+            escrow_account.initializer.as_ref(),
+            escrow_account.offer_id.to_le_bytes().as_ref()
+        ],
+        bump = escrow_account.vault_bump,
+    )]
+    pub vault: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        seeds = [
+            b"escrow",
+            escrow_account.initializer.as_ref(),
+            escrow_account.offer_id.to_le_bytes().as_ref()
+        ],
+        bump = escrow_account.escrow_bump,
+        has_one = initializer,
+        has_one = initializer_token_account,
+        has_one = mint,
+        close = initializer
+    )]
+    pub escrow_account: Account<'info, EscrowAccount>,
+
+    #[account(mut)]
+    pub mint: InterfaceAccount<'info, Mint>,
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[event_cpi]
+#[derive(Accounts)]
+pub struct CommitBid<'info> {
+    #[account(mut)]
+    pub bidder: Signer<'info>,
+
+    #[account(
+        init,
+        payer = bidder,
+        space = 8 + SealedBid::INIT_SPACE,
+        seeds = [b"sealed_bid", escrow_account.key().as_ref(), bidder.key().as_ref()],
+        bump
+    )]
+    pub sealed_bid: Account<'info, SealedBid>,
+
+    #[account(
+        mut,
+        seeds = [
+            b"escrow",
+            escrow_account.initializer.as_ref(),
+            escrow_account.offer_id.to_le_bytes().as_ref()
+        ],
+        bump = escrow_account.escrow_bump,
+    )]
+    pub escrow_account: Account<'info, EscrowAccount>,
+
+    #[account(seeds = [b"config"], bump = config.bump)]
+    pub config: Account<'info, Config>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[event_cpi]
+#[derive(Accounts)]
+pub struct RevealBid<'info> {
+    pub bidder: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"sealed_bid", escrow_account.key().as_ref(), bidder.key().as_ref()],
+        bump = sealed_bid.bump,
+        has_one = bidder,
+    )]
+    pub sealed_bid: Account<'info, SealedBid>,
+
+    #[account(
+        mut,
+        seeds = [
+            b"escrow",
+            escrow_account.initializer.as_ref(),
+            escrow_account.offer_id.to_le_bytes().as_ref()
+        ],
+        bump = escrow_account.escrow_bump,
+    )]
+    pub escrow_account: Account<'info, EscrowAccount>,
+}
+
+#[event_cpi]
+#[derive(Accounts)]
+pub struct SettleSealedAuction<'info> {
+    pub caller: Signer<'info>,
+
+    /// CHECK: This is the initializer who gets the proceeds and rent back
+    #[account(mut)]
+    pub initializer: UncheckedAccount<'info>,
+
+    #[account(mut)]
+    pub initializer_token_account: InterfaceAccount<'info, TokenAccount>,
+
+    /// CHECK: Checked against the auction's highest bidder, gets the rest of the deposit back
+    #[account(mut)]
+    pub winner: Option<UncheckedAccount<'info>>,
+
+    #[account(mut)]
+    pub winner_token_account: Option<InterfaceAccount<'info, TokenAccount>>,
+
+    #[account(
+        mut,
+        seeds = [b"sealed_bid", escrow_account.key().as_ref(), winning_bid.bidder.as_ref()],
+        bump = winning_bid.bump,
+    )]
+    pub winning_bid: Option<Account<'info, SealedBid>>,
+
+    #[account(seeds = [b"config"], bump = config.bump)]
+    pub config: Account<'info, Config>,
+
+    /// CHECK: Receives the protocol fee, checked against the config
+    #[account(mut, address = config.treasury)]
+    pub treasury: UncheckedAccount<'info>,
+
+    #[account(
+        mut,
+        seeds = [
+            b"vault",
+            escrow_account.initializer.as_ref(),
+            escrow_account.offer_id.to_le_bytes().as_ref()
+        ],
+        bump = escrow_account.vault_bump,
+    )]
+    pub vault: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        seeds = [
+            b"escrow",
+            escrow_account.initializer.as_ref(),
+            escrow_account.offer_id.to_le_bytes().as_ref()
+        ],
+        bump = escrow_account.escrow_bump,
+        has_one = initializer,
+        has_one = initializer_token_account,
+        has_one = mint,
+    )]
+    pub escrow_account: Account<'info, EscrowAccount>,
+
+    #[account(mut)]
+    pub mint: InterfaceAccount<'info, Mint>,
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[event_cpi]
+#[derive(Accounts)]
+pub struct CloseSealedBid<'info> {
+    pub caller: Signer<'info>,
+
+    /// CHECK: Owner of the sealed bid, gets the refund and rent back
+    #[account(mut)]
+    pub bidder: UncheckedAccount<'info>,
+
+    /// CHECK: This is the initializer who gets forfeited deposits
+    #[account(mut)]
+    pub initializer: UncheckedAccount<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"sealed_bid", escrow_account.key().as_ref(), bidder.key().as_ref()],
+        bump = sealed_bid.bump,
+        has_one = bidder,
+        close = bidder
+    )]
+    pub sealed_bid: Account<'info, SealedBid>,
+
+    #[account(
+        mut,
+        seeds = [
+            b"escrow",
+            escrow_account.initializer.as_ref(),
+            escrow_account.offer_id.to_le_bytes().as_ref()
+        ],
+        bump = escrow_account.escrow_bump,
+        has_one = initializer,
+    )]
+    pub escrow_account: Account<'info, EscrowAccount>,
+}
+
+#[event_cpi]
+#[derive(Accounts)]
+pub struct ClaimHtlc<'info> {
+    pub counterparty: Signer<'info>,
+
+    #[account(
+        mut,
+        constraint = counterparty_token_account.owner == counterparty.key(),
+        constraint = counterparty_token_account.mint == mint.key()
+    )]
+    pub counterparty_token_account: InterfaceAccount<'info, TokenAccount>,
+
+    /// CHECK: This is the initializer who gets the rent back
+    #[account(mut)]
+    pub initializer: UncheckedAccount<'info>,
+
+    #[account(
+        mut,
+        seeds = [
+            b"vault",
+            escrow_account.initializer.as_ref(),
+            escrow_account.offer_id.to_le_bytes().as_ref()
+        ],
+        bump = escrow_account.vault_bump,
+    )]
+    pub vault: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        seeds = [
+            b"escrow",
+            escrow_account.initializer.as_ref(),
+            escrow_account.offer_id.to_le_bytes().as_ref()
+        ],
+        bump = escrow_account.escrow_bump,
+        has_one = initializer,
+        has_one = mint,
+        close = initializer
+    )]
+    pub escrow_account: Account<'info, EscrowAccount>,
+
+    #[account(mut)]
+    pub mint: InterfaceAccount<'info, Mint>,
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[event_cpi]
+#[derive(Accounts)]
+pub struct RaiseDispute<'info> {
+    /// Alice or the allowed taker
+    pub party: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [
+            b"escrow",
+            escrow_account.initializer.as_ref(),
+            escrow_account.offer_id.to_le_bytes().as_ref()
+        ],
+        bump = escrow_account.escrow_bump,
+    )]
+    pub escrow_account: Account<'info, EscrowAccount>,
+}
+
+#[event_cpi]
+#[derive(Accounts)]
+pub struct ResolveDispute<'info> {
+    pub arbiter: Signer<'info>,
+
+    /// CHECK: This is the initializer who gets the refund share and rent back
+    #[account(mut)]
+    pub initializer: UncheckedAccount<'info>,
+
+    #[account(mut)]
+    pub initializer_token_account: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        constraint = escrow_account.allowed_taker == Some(taker_token_account.owner) @ EscrowError::TakerNotAllowed,
+        constraint = taker_token_account.mint == mint.key()
+    )]
+    pub taker_token_account: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        seeds = [
+            b"vault",
+            escrow_account.initializer.as_ref(),
+            escrow_account.offer_id.to_le_bytes().as_ref()
+        ],
+        bump = escrow_account.vault_bump,
+    )]
+    pub vault: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        seeds = [
+            b"escrow",
+            escrow_account.initializer.as_ref(),
+            escrow_account.offer_id.to_le_bytes().as_ref()
+        ],
+        bump = escrow_account.escrow_bump,
+        has_one = initializer,
+        has_one = initializer_token_account,
+        has_one = mint,
+        constraint = escrow_account.arbiter == Some(arbiter.key()) @ EscrowError::Unauthorized,
+        close = initializer
+    )]
+    pub escrow_account: Account<'info, EscrowAccount>,
+
+    #[account(mut)]
+    pub mint: InterfaceAccount<'info, Mint>,
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[event_cpi]
+#[derive(Accounts)]
+pub struct Approve<'info> {
+    pub approver: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [
+            b"escrow",
+            escrow_account.initializer.as_ref(),
+            escrow_account.offer_id.to_le_bytes().as_ref()
+        ],
+        bump = escrow_account.escrow_bump,
+    )]
+    pub escrow_account: Account<'info, EscrowAccount>,
+}
+
+#[event_cpi]
+#[derive(Accounts)]
+pub struct ExecuteMultisig<'info> {
+    pub caller: Signer<'info>,
+
+    /// CHECK: This is the initializer who gets the rent back
+    #[account(mut)]
+    pub initializer: UncheckedAccount<'info>,
+
+    /// Beneficiary's token account on release, Alice's original one on refund
+    #[account(mut, constraint = recipient_token_account.mint == mint.key())]
+    pub recipient_token_account: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        seeds = [
+            b"vault",
+            escrow_account.initializer.as_ref(),
+            escrow_account.offer_id.to_le_bytes().as_ref()
+        ],
+        bump = escrow_account.vault_bump,
+    )]
+    pub vault: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        seeds = [
+            b"escrow",
+            escrow_account.initializer.as_ref(),
+            escrow_account.offer_id.to_le_bytes().as_ref()
+        ],
+        bump = escrow_account.escrow_bump,
+        has_one = initializer,
+        has_one = mint,
+        close = initializer
+    )]
+    pub escrow_account: Account<'info, EscrowAccount>,
+
+    #[account(mut)]
+    pub mint: InterfaceAccount<'info, Mint>,
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[event_cpi]
+#[derive(Accounts)]
+pub struct ReleaseMilestone<'info> {
+    /// Alice or the milestone approver
+    pub authority: Signer<'info>,
+
+    /// CHECK: This is the initializer who gets the rent back after the last milestone
+    #[account(mut)]
+    pub initializer: UncheckedAccount<'info>,
+
+    #[account(mut, constraint = beneficiary_token_account.mint == mint.key())]
+    pub beneficiary_token_account: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        seeds = [
+            b"vault",
+            escrow_account.initializer.as_ref(),
+            escrow_account.offer_id.to_le_bytes().as_ref()
+        ],
+        bump = escrow_account.vault_bump,
+    )]
+    pub vault: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        seeds = [
+            b"escrow",
+            escrow_account.initializer.as_ref(),
+            escrow_account.offer_id.to_le_bytes().as_ref()
+        ],
+        bump = escrow_account.escrow_bump,
+        has_one = initializer,
+        has_one = mint,
+    )]
+    pub escrow_account: Account<'info, EscrowAccount>,
+
+    #[account(mut)]
+    pub mint: InterfaceAccount<'info, Mint>,
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[event_cpi]
+#[derive(Accounts)]
+pub struct Claim<'info> {
+    pub beneficiary: Signer<'info>,
+
+    #[account(
+        mut,
+        constraint = beneficiary_token_account.owner == beneficiary.key(),
+        constraint = beneficiary_token_account.mint == mint.key()
+    )]
+    pub beneficiary_token_account: InterfaceAccount<'info, TokenAccount>,
+
+    /// CHECK: This is the initializer who gets the rent back after the last claim
+    #[account(mut)]
+    pub initializer: UncheckedAccount<'info>,
+
+    #[account(
+        mut,
+        seeds = [
+            b"vault",
+            escrow_account.initializer.as_ref(),
+            escrow_account.offer_id.to_le_bytes().as_ref()
+        ],
+        bump = escrow_account.vault_bump,
+    )]
+    pub vault: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        seeds = [
+            b"escrow",
+            escrow_account.initializer.as_ref(),
+            escrow_account.offer_id.to_le_bytes().as_ref()
+        ],
+        bump = escrow_account.escrow_bump,
+        has_one = initializer,
+        has_one = mint,
+    )]
+    pub escrow_account: Account<'info, EscrowAccount>,
+
+    #[account(mut)]
+    pub mint: InterfaceAccount<'info, Mint>,
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[event_cpi]
+#[derive(Accounts)]
+pub struct Revoke<'info> {
+    #[account(mut)]
+    pub initializer: Signer<'info>,
+
+    #[account(mut)]
+    pub initializer_token_account: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        seeds = [
+            b"vault",
+            escrow_account.initializer.as_ref(),
+            escrow_account.offer_id.to_le_bytes().as_ref()
+        ],
+        bump = escrow_account.vault_bump,
+    )]
+    pub vault: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        seeds = [
+            b"escrow",
+            initializer.key().as_ref(),
+            escrow_account.offer_id.to_le_bytes().as_ref()
+        ],
+        bump = escrow_account.escrow_bump,
+        has_one = initializer,
+        has_one = initializer_token_account,
+        has_one = mint,
+    )]
+    pub escrow_account: Account<'info, EscrowAccount>,
+
+    #[account(mut)]
+    pub mint: InterfaceAccount<'info, Mint>,
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[account]
+#[derive(InitSpace)]
+pub struct EscrowAccount {
+    pub initializer: Pubkey,
+    pub offer_id: u64,
+    pub initializer_token_account: Pubkey,
+    pub amount_to_send: u64,
+    pub amount_to_receive: u64,
+    pub mint: Pubkey,
+    pub escrow_bump: u8,
+    pub vault_bump: u8,
+    pub is_completed: bool,
+    pub expires_at: Option<i64>,
+    pub remaining_to_send: u64,
+    pub payment_mint: Option<Pubkey>,
+    pub allowed_taker: Option<Pubkey>,
+    #[max_len(MAX_PAYOUT_SPLITS)]
+    pub payouts: Vec<PayoutSplit>,
+    pub dutch_auction: Option<DutchAuction>,
+    pub mode: EscrowMode,
+    pub arbiter: Option<Pubkey>,
+    pub disputed: bool,
+}
+
+/// How an escrow gets settled
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, InitSpace)]
+pub enum EscrowMode {
+    /// Filled by takers through `exchange` at the escrow's price
+    Offer,
+    /// Sold to the highest bidder through `place_bid` and `settle_auction`
+    EnglishAuction(EnglishAuction),
+    /// Sold to the highest revealed bid through `commit_bid`, `reveal_bid` and `settle_sealed_auction`
+    SealedBidAuction(SealedBidAuction),
+    /// Claimed by the counterparty with a preimage through `claim_htlc`, refunded after the timelock
+    Htlc(Htlc),
+    /// Released or refunded once enough approvers agree, through `approve` and `execute_multisig`
+    Multisig(Multisig),
+    /// Released to a beneficiary one milestone at a time through `release_milestone`
+    Milestones(MilestonePlan),
+    /// Vests linearly to a beneficiary, claimed through `claim`
+    Vesting(Vesting),
+}
+
+/// Ascending auction for the whole vault, bids are in lamports
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, InitSpace)]
+pub struct EnglishAuction {
+    pub reserve_price: u64,
+    pub min_increment: u64,
+    pub end_ts: i64,
+    pub highest_bidder: Option<Pubkey>,
+    pub highest_bid: u64,
+}
+
+/// Commit-reveal auction for the whole vault, bids are in lamports
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, InitSpace)]
+pub struct SealedBidAuction {
+    pub reserve_price: u64,
+    pub commit_end_ts: i64,
+    pub reveal_end_ts: i64,
+    /// Winner pays the runner-up's bid (or the reserve) instead of their own
+    pub second_price: bool,
+    /// Sealed bid PDAs that haven't been closed yet
+    pub bid_count: u32,
+    pub highest_bidder: Option<Pubkey>,
+    pub highest_bid: u64,
+    pub second_bid: u64,
+}
+
+/// Hash time lock for cross-chain atomic swaps
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, InitSpace)]
+pub struct Htlc {
+    pub counterparty: Pubkey,
+    /// SHA-256 hash of the 32-byte secret
+    pub hashlock: [u8; 32],
+    /// Claims are accepted before this unix timestamp, refunds from it on
+    pub timelock: i64,
+}
+
+/// M-of-N approval over where the vault goes
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, InitSpace)]
+pub struct Multisig {
+    pub beneficiary: Pubkey,
+    #[max_len(MAX_APPROVERS)]
+    pub approvers: Vec<Pubkey>,
+    pub threshold: u8,
+    /// Bit `i` is set once `approvers[i]` approved releasing to the beneficiary
+    pub release_approvals: u8,
+    /// Bit `i` is set once `approvers[i]` approved refunding Alice
+    pub refund_approvals: u8,
+}
+
+/// Outcome a multisig approver signs off on
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy)]
+pub enum MultisigAction {
+    Release,
+    Refund,
+}
+
+/// Milestone payouts to a single beneficiary
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, InitSpace)]
+pub struct MilestonePlan {
+    pub beneficiary: Pubkey,
+    /// Can release milestones besides Alice
+    pub approver: Option<Pubkey>,
+    #[max_len(MAX_MILESTONES)]
+    pub milestones: Vec<Milestone>,
+    /// Bit `i` is set once `milestones[i]` has been released
+    pub released: u16,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, InitSpace)]
+pub struct Milestone {
+    pub amount: u64,
+    /// Hash of the off-chain description of the deliverable
+    pub description_hash: [u8; 32],
+}
+
+/// Linear vesting schedule for the whole vault
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, InitSpace)]
+pub struct Vesting {
+    pub beneficiary: Pubkey,
+    pub start_ts: i64,
+    /// Nothing is claimable before this, defaults to `start_ts`
+    pub cliff_ts: Option<i64>,
+    pub end_ts: i64,
+    pub revocable: bool,
+    pub claimed: u64,
+    /// Vesting stops at this timestamp once Alice revokes
+    pub revoked_at: Option<i64>,
+}
+
+impl Vesting {
+    /// Part of `total` vested at `now`, rounded down in Alice's favor
+    pub fn vested_amount(&self, total: u64, now: i64) -> u64 {
+        let now = self.revoked_at.map_or(now, |revoked_at| now.min(revoked_at));
+
+        if now < self.cliff_ts.unwrap_or(self.start_ts) {
+            return 0;
+        }
+        if now >= self.end_ts {
+            return total;
+        }
+
+        let elapsed = (now - self.start_ts) as u128;
+        let duration = (self.end_ts - self.start_ts) as u128;
+
+        (total as u128 * elapsed / duration) as u64
+    }
+}
+
+impl SealedBidAuction {
+    /// What the winner pays, never below the reserve
+    pub fn clearing_price(&self) -> u64 {
+        if self.second_price {
+            self.second_bid.max(self.reserve_price)
+        } else {
+            self.highest_bid
+        }
+    }
+}
+
+/// How the arbiter settles a disputed escrow
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy)]
+pub enum DisputeResolution {
+    /// Everything left in the vault goes to the taker
+    Release,
+    /// Everything left in the vault goes back to Alice
+    Refund,
+    /// `taker_bps` of the vault goes to the taker, the rest back to Alice
+    Split { taker_bps: u16 },
+}
+
+impl DisputeResolution {
+    /// Share of the vault going to the taker
+    pub fn taker_bps(&self) -> u16 {
+        match self {
+            DisputeResolution::Release => BPS_DENOMINATOR as u16,
+            DisputeResolution::Refund => 0,
+            DisputeResolution::Split { taker_bps } => *taker_bps,
+        }
+    }
+}
+
+/// Descending price for the whole offer, from `start_price` at `start_ts`
+/// down to `end_price` at `end_ts`
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, InitSpace)]
+pub struct DutchAuction {
+    pub start_price: u64,
+    pub end_price: u64,
+    pub start_ts: i64,
+    pub end_ts: i64,
+    /// Seconds between price drops, 0 decays linearly every second
+    pub step_interval: i64,
+}
+
+impl DutchAuction {
+    /// Price for the whole offer at `now`
+    pub fn price_at(&self, now: i64) -> u64 {
+        if now <= self.start_ts {
+            return self.start_price;
+        }
+        if now >= self.end_ts {
+            return self.end_price;
+        }
+
+        let mut elapsed = now - self.start_ts;
+        if self.step_interval > 0 {
+            elapsed -= elapsed % self.step_interval;
+        }
+        let duration = self.end_ts - self.start_ts;
+        let decay = (self.start_price - self.end_price) as u128 * elapsed as u128 / duration as u128;
+
+        self.start_price - decay as u64
+    }
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, InitSpace)]
+pub struct PayoutSplit {
+    pub recipient: Pubkey,
+    pub bps: u16,
+}
+
+#[account]
+#[derive(InitSpace)]
+pub struct Config {
+    pub admin: Pubkey,
+    pub treasury: Pubkey,
+    pub fee_bps: u16,
+    pub bump: u8,
+    pub paused: bool,
+    pub pending_admin: Option<Pubkey>,
+}
+
+impl Config {
+    /// Protocol fee on `amount`, rounded down in the initializer's favor
+    pub fn fee_for(&self, amount: u64) -> Result<u64> {
+        let fee = (amount as u128 * self.fee_bps as u128) / BPS_DENOMINATOR as u128;
+
+        u64::try_from(fee).map_err(|_| error!(EscrowError::MathOverflow))
+    }
+}
+
+impl EscrowAccount {
+    /// Space of an offer without payout splits. `INIT_SPACE` assumes the most payout
+    /// splits and the largest mode, escrows start at this and grow to `space()` instead.
+    pub const BASE_SPACE: usize = Self::INIT_SPACE
+        - MAX_PAYOUT_SPLITS * PayoutSplit::INIT_SPACE
+        - EscrowMode::INIT_SPACE
+        + 1;
+
+    /// Space this escrow needs, with payout splits and mode lists sized to what they hold
+    pub fn space(&self) -> usize {
+        let mode = match &self.mode {
+            EscrowMode::Offer => 0,
+            EscrowMode::EnglishAuction(_) => EnglishAuction::INIT_SPACE,
+            EscrowMode::SealedBidAuction(_) => SealedBidAuction::INIT_SPACE,
+            EscrowMode::Htlc(_) => Htlc::INIT_SPACE,
+            EscrowMode::Multisig(multisig) => {
+                Multisig::INIT_SPACE - (MAX_APPROVERS - multisig.approvers.len()) * 32
+            }
+            EscrowMode::Milestones(plan) => {
+                MilestonePlan::INIT_SPACE
+                    - (MAX_MILESTONES - plan.milestones.len()) * Milestone::INIT_SPACE
+            }
+            EscrowMode::Vesting(_) => Vesting::INIT_SPACE,
+        };
+
+        Self::BASE_SPACE + self.payouts.len() * PayoutSplit::INIT_SPACE + mode
+    }
+
+    /// Whether takers fill this escrow through `exchange`
+    pub fn is_offer(&self) -> bool {
+        matches!(self.mode, EscrowMode::Offer)
+    }
+
+    pub fn is_expired(&self, now: i64) -> bool {
+        self.expires_at.is_some_and(|expires_at| now >= expires_at)
+    }
+
+    /// Mint a taker's payment token account must hold, wSOL for SOL-priced escrows
+    pub fn payment_mint_or_native(&self) -> Pubkey {
+        self.payment_mint.unwrap_or(native_mint::ID)
+    }
+
+    /// Price for the whole offer at `now`, following the Dutch auction if there is one
+    pub fn amount_to_receive_at(&self, now: i64) -> u64 {
+        self.dutch_auction
+            .map_or(self.amount_to_receive, |auction| auction.price_at(now))
+    }
+
+    /// Payment owed for `amount` tokens delivered, pro-rata over the full offer.
+    /// Rounds up so partial fills never shortchange the initializer.
+    pub fn price_for(&self, amount: u64, now: i64) -> Result<u64> {
+        let price = (self.amount_to_receive_at(now) as u128 * amount as u128)
+            .div_ceil(self.amount_to_send as u128);
+
+        u64::try_from(price).map_err(|_| error!(EscrowError::MathOverflow))
+    }
+
+    /// Split `amount` across the payout splits by bps, the last split takes the rounding dust
+    pub fn split_payout(&self, amount: u64) -> Vec<u64> {
+        let mut remaining = amount;
+
+        self.payouts
+            .iter()
+            .enumerate()
+            .map(|(index, split)| {
+                let share = if index + 1 == self.payouts.len() {
+                    remaining
+                } else {
+                    (amount as u128 * split.bps as u128 / BPS_DENOMINATOR as u128) as u64
+                };
+                remaining -= share;
+                share
+            })
+            .collect()
+    }
+}
+
+/// Escrow layout from before `offer_id` existed, seeded by `[b"escrow", initializer]`.
+/// Shares `EscrowAccount`'s discriminator, so it is told apart by its size.
+/// Only loaded by `close_completed` and `cancel_legacy`, never written.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct LegacyEscrowAccount {
+    pub initializer: Pubkey,
+    pub initializer_token_account: Pubkey,
+    pub amount_to_send: u64,
+    pub amount_to_receive: u64,
+    pub mint: Pubkey,
+    pub escrow_bump: u8,
+    pub vault_bump: u8,
+    pub is_completed: bool,
+}
+
+impl LegacyEscrowAccount {
+    pub const LEN: usize = 32 + 32 + 8 + 8 + 32 + 1 + 1 + 1;
+
+    /// Parse a legacy escrow, checking its owner, discriminator and size
+    pub fn load(info: &AccountInfo) -> Result<Self> {
+        require_keys_eq!(*info.owner, crate::ID, EscrowError::InvalidLegacyEscrow);
+
+        let data = info.try_borrow_data()?;
+        require!(
+            data.len() == 8 + Self::LEN && data.starts_with(EscrowAccount::DISCRIMINATOR),
+            EscrowError::InvalidLegacyEscrow
+        );
+
+        Self::try_from_slice(&data[8..]).map_err(|_| error!(EscrowError::InvalidLegacyEscrow))
+    }
+
+    /// Close a legacy escrow, its rent goes to `destination`
+    pub fn close<'info>(info: &AccountInfo<'info>, destination: &AccountInfo<'info>) -> Result<()> {
+        withdraw_lamports(info, destination, info.lamports())?;
+        info.assign(&system_program::ID);
+        info.resize(0)?;
+
+        Ok(())
+    }
+}
+
+/// Standing buy order, the locked SOL sits in this account on top of its rent
+#[account]
+#[derive(InitSpace)]
+pub struct BidEscrow {
+    pub buyer: Pubkey,
+    pub offer_id: u64,
+    pub buyer_token_account: Pubkey,
+    pub mint: Pubkey,
+    pub token_amount: u64,
+    pub lamports: u64,
+    pub bump: u8,
+}
+
+/// Bid on an English auction, the bid sits in this account on top of its rent
+#[account]
+#[derive(InitSpace)]
+pub struct AuctionBid {
+    pub escrow: Pubkey,
+    pub bidder: Pubkey,
+    pub amount: u64,
+    pub bump: u8,
+}
+
+/// Sealed bid on a commit-reveal auction, the deposit sits in this account on top of its rent
+#[account]
+#[derive(InitSpace)]
+pub struct SealedBid {
+    pub escrow: Pubkey,
+    pub bidder: Pubkey,
+    pub commitment: [u8; 32],
+    pub deposit: u64,
+    pub revealed: bool,
+    pub amount: u64,
+    pub bump: u8,
+}
+
+#[event]
+pub struct ConfigInitialized {
+    pub admin: Pubkey,
+    pub treasury: Pubkey,
+    pub fee_bps: u16,
+}
+
+#[event]
+pub struct FeeUpdated {
+    pub treasury: Pubkey,
+    pub fee_bps: u16,
+}
+
+#[event]
+pub struct PausedSet {
+    pub paused: bool,
+}
+
+#[event]
+pub struct AdminTransferStarted {
+    pub admin: Pubkey,
+    pub pending_admin: Pubkey,
+}
+
+#[event]
+pub struct AdminTransferred {
+    pub previous_admin: Pubkey,
+    pub admin: Pubkey,
+}
+
+#[event]
+pub struct EscrowInitialized {
+    pub escrow: Pubkey,
+    pub initializer: Pubkey,
+    pub offer_id: u64,
+    pub mint: Pubkey,
+    pub payment_mint: Option<Pubkey>,
+    pub amount_to_send: u64,
+    pub amount_to_receive: u64,
+    pub expires_at: Option<i64>,
+    pub allowed_taker: Option<Pubkey>,
+    pub dutch_auction: Option<DutchAuction>,
+    pub arbiter: Option<Pubkey>,
+}
+
+#[event]
+pub struct EscrowExchanged {
+    pub escrow: Pubkey,
+    pub initializer: Pubkey,
+    pub taker: Pubkey,
+    pub mint: Pubkey,
+    pub payment_mint: Option<Pubkey>,
+    pub fill_amount: u64,
+    pub received_amount: u64,
+    pub payment: u64,
+    pub protocol_fee: u64,
+    pub remaining_to_send: u64,
+}
+
+#[event]
+pub struct EscrowUpdated {
+    pub escrow: Pubkey,
+    pub initializer: Pubkey,
+    pub amount_to_send: u64,
+    pub amount_to_receive: u64,
+    pub remaining_to_send: u64,
+}
+
+#[event]
+pub struct EscrowCancelled {
+    pub escrow: Pubkey,
+    pub initializer: Pubkey,
+    pub mint: Pubkey,
+    pub amount_returned: u64,
+}
+
+#[event]
+pub struct CompletedEscrowClosed {
+    pub escrow: Pubkey,
+    pub initializer: Pubkey,
+}
+
+#[event]
+pub struct EscrowExpiredReclaimed {
+    pub escrow: Pubkey,
+    pub initializer: Pubkey,
+    pub caller: Pubkey,
+    pub mint: Pubkey,
+    pub amount_returned: u64,
+}
+
+#[event]
+pub struct BidInitialized {
+    pub bid: Pubkey,
+    pub buyer: Pubkey,
+    pub offer_id: u64,
+    pub mint: Pubkey,
+    pub token_amount: u64,
+    pub lamports: u64,
+}
+
+#[event]
+pub struct BidFilled {
+    pub bid: Pubkey,
+    pub buyer: Pubkey,
+    pub seller: Pubkey,
+    pub mint: Pubkey,
+    pub token_amount: u64,
+    pub lamports: u64,
+    pub protocol_fee: u64,
+}
+
+#[event]
+pub struct BidCancelled {
+    pub bid: Pubkey,
+    pub buyer: Pubkey,
+    pub lamports_returned: u64,
+}
+
+#[event]
+pub struct EnglishAuctionInitialized {
+    pub escrow: Pubkey,
+    pub initializer: Pubkey,
+    pub offer_id: u64,
+    pub mint: Pubkey,
+    pub amount_to_send: u64,
+    pub reserve_price: u64,
+    pub min_increment: u64,
+    pub end_ts: i64,
+}
+
+#[event]
+pub struct AuctionBidPlaced {
+    pub escrow: Pubkey,
+    pub bidder: Pubkey,
+    pub amount: u64,
+    pub outbid: Option<Pubkey>,
+}
+
+#[event]
+pub struct AuctionSettled {
+    pub escrow: Pubkey,
+    pub initializer: Pubkey,
+    pub winner: Option<Pubkey>,
+    pub winning_bid: u64,
+    pub token_amount: u64,
+    pub protocol_fee: u64,
+}
+
+#[event]
+pub struct SealedAuctionInitialized {
+    pub escrow: Pubkey,
+    pub initializer: Pubkey,
+    pub offer_id: u64,
+    pub mint: Pubkey,
+    pub amount_to_send: u64,
+    pub reserve_price: u64,
+    pub commit_end_ts: i64,
+    pub reveal_end_ts: i64,
+    pub second_price: bool,
+}
+
+#[event]
+pub struct SealedBidCommitted {
+    pub escrow: Pubkey,
+    pub bidder: Pubkey,
+    pub deposit: u64,
+}
+
+#[event]
+pub struct SealedBidRevealed {
+    pub escrow: Pubkey,
+    pub bidder: Pubkey,
+    pub amount: u64,
+}
+
+#[event]
+pub struct SealedAuctionSettled {
+    pub escrow: Pubkey,
+    pub initializer: Pubkey,
+    pub winner: Option<Pubkey>,
+    pub price: u64,
+    pub token_amount: u64,
+    pub protocol_fee: u64,
+}
+
+#[event]
+pub struct SealedBidClosed {
+    pub escrow: Pubkey,
+    pub bidder: Pubkey,
+    pub refunded: u64,
+    pub forfeited: u64,
+}
+
+#[event]
+pub struct HtlcInitialized {
+    pub escrow: Pubkey,
+    pub initializer: Pubkey,
+    pub offer_id: u64,
+    pub mint: Pubkey,
+    pub amount_to_send: u64,
+    pub counterparty: Pubkey,
+    pub hashlock: [u8; 32],
+    pub timelock: i64,
+}
+
+#[event]
+pub struct HtlcClaimed {
+    pub escrow: Pubkey,
+    pub initializer: Pubkey,
+    pub counterparty: Pubkey,
+    pub mint: Pubkey,
+    pub amount: u64,
+    pub hashlock: [u8; 32],
+    pub preimage: [u8; 32],
+}
+
+#[event]
+pub struct DisputeRaised {
+    pub escrow: Pubkey,
+    pub raised_by: Pubkey,
+}
+
+#[event]
+pub struct DisputeResolved {
+    pub escrow: Pubkey,
+    pub arbiter: Pubkey,
+    pub taker_bps: u16,
+    pub taker_amount: u64,
+    pub initializer_amount: u64,
+}
+
+#[event]
+pub struct MultisigEscrowInitialized {
+    pub escrow: Pubkey,
+    pub initializer: Pubkey,
+    pub offer_id: u64,
+    pub mint: Pubkey,
+    pub amount_to_send: u64,
+    pub beneficiary: Pubkey,
+    pub approvers: Vec<Pubkey>,
+    pub threshold: u8,
+}
+
+#[event]
+pub struct MultisigApproved {
+    pub escrow: Pubkey,
+    pub approver: Pubkey,
+    pub action: MultisigAction,
+    pub approval_count: u8,
+}
+
+#[event]
+pub struct MultisigExecuted {
+    pub escrow: Pubkey,
+    pub action: MultisigAction,
+    pub recipient_token_account: Pubkey,
+    pub amount: u64,
+}
+
+#[event]
+pub struct MilestoneEscrowInitialized {
+    pub escrow: Pubkey,
+    pub initializer: Pubkey,
+    pub offer_id: u64,
+    pub mint: Pubkey,
+    pub amount_to_send: u64,
+    pub beneficiary: Pubkey,
+    pub approver: Option<Pubkey>,
+    pub milestones: Vec<Milestone>,
+}
+
+#[event]
+pub struct MilestoneReleased {
+    pub escrow: Pubkey,
+    pub released_by: Pubkey,
+    pub index: u8,
+    pub amount: u64,
+    pub remaining_to_send: u64,
+}
+
+#[event]
+pub struct VestingInitialized {
+    pub escrow: Pubkey,
+    pub initializer: Pubkey,
+    pub offer_id: u64,
+    pub mint: Pubkey,
+    pub amount_to_send: u64,
+    pub beneficiary: Pubkey,
+    pub start_ts: i64,
+    pub cliff_ts: Option<i64>,
+    pub end_ts: i64,
+    pub revocable: bool,
+}
+
+#[event]
+pub struct VestedTokensClaimed {
+    pub escrow: Pubkey,
+    pub beneficiary: Pubkey,
+    pub amount: u64,
+    pub total_claimed: u64,
+}
+
+#[event]
+pub struct VestingRevoked {
+    pub escrow: Pubkey,
+    pub initializer: Pubkey,
+    pub unvested_returned: u64,
+    pub vested: u64,
+}
+
+#[error_code]
+pub enum EscrowError {
+    #[msg("Escrow has already been completed")]
+    AlreadyCompleted,
+    #[msg("Escrow has not been completed")]
+    NotCompleted,
+    #[msg("Expiry must be in the future")]
+    InvalidExpiry,
+    #[msg("Escrow has expired")]
+    Expired,
+    #[msg("Escrow has not expired yet")]
+    NotExpired,
+    #[msg("Amount must be greater than zero")]
+    InvalidAmount,
+    #[msg("Fill amount must be greater than zero and at most the remaining amount")]
+    InvalidFillAmount,
+    #[msg("Arithmetic overflow")]
+    MathOverflow,
+    #[msg("Payment token accounts are required for this escrow")]
+    MissingPaymentAccount,
+    #[msg("Payment token account does not match the escrow payment mint")]
+    PaymentMintMismatch,
+    #[msg("Taker is not allowed to fill this escrow")]
+    TakerNotAllowed,
+    #[msg("Escrow mint does not match the expected mint")]
+    MintMismatch,
+    #[msg("Escrow terms are worse than the taker's limits")]
+    SlippageExceeded,
+    #[msg("Fee exceeds the maximum allowed")]
+    FeeTooHigh,
+    #[msg("Program is paused")]
+    Paused,
+    #[msg("Signer is not authorized for this action")]
+    Unauthorized,
+    #[msg("Payout splits must be at most the maximum count, non-zero and sum to 10000 bps")]
+    InvalidPayoutSplits,
+    #[msg("Payout recipient account does not match the escrow payout splits")]
+    InvalidPayoutAccount,
+    #[msg("Auction parameters are invalid")]
+    InvalidAuction,
+    #[msg("Auction terms cannot be changed")]
+    AuctionTermsLocked,
+    #[msg("Instruction does not apply to this kind of escrow")]
+    InvalidEscrowMode,
+    #[msg("Auction has ended")]
+    AuctionEnded,
+    #[msg("Auction has not ended yet")]
+    AuctionNotEnded,
+    #[msg("Bid is below the reserve price or minimum increment")]
+    BidTooLow,
+    #[msg("Bid accounts do not match the auction's highest bid")]
+    InvalidBidAccount,
+    #[msg("Auction already has bids")]
+    AuctionHasBids,
+    #[msg("Auction is not in its reveal phase")]
+    NotRevealPhase,
+    #[msg("Revealed bid does not match its commitment")]
+    InvalidCommitment,
+    #[msg("Bid has already been revealed")]
+    BidAlreadyRevealed,
+    #[msg("Deposit does not cover the revealed bid")]
+    DepositTooLow,
+    #[msg("Preimage does not match the hashlock")]
+    InvalidPreimage,
+    #[msg("Timelock has not expired yet")]
+    TimelockNotExpired,
+    #[msg("Arbiter needs an allowed taker and must differ from both parties")]
+    InvalidArbiter,
+    #[msg("Escrow has no arbiter")]
+    NoArbiter,
+    #[msg("Escrow is under dispute")]
+    Disputed,
+    #[msg("Escrow is not under dispute")]
+    NotDisputed,
+    #[msg("Taker share exceeds 100%")]
+    InvalidResolution,
+    #[msg("Approvers must be unique, at most 8, with a threshold between 1 and their count")]
+    InvalidApprovers,
+    #[msg("Approver has already approved this action")]
+    AlreadyApproved,
+    #[msg("Not enough approvals for this action")]
+    ThresholdNotReached,
+    #[msg("Milestones must be 1 to 16 non-zero amounts")]
+    InvalidMilestones,
+    #[msg("Milestone does not exist")]
+    MilestoneNotFound,
+    #[msg("Milestone has already been released")]
+    MilestoneAlreadyReleased,
+    #[msg("Vesting must end after it starts, with the cliff in between")]
+    InvalidVestingSchedule,
+    #[msg("No vested tokens to claim")]
+    NothingToClaim,
+    #[msg("Vesting is not revocable")]
+    NotRevocable,
+    #[msg("Vesting has already been revoked")]
+    AlreadyRevoked,
+    #[msg("Account is not a legacy escrow or does not match it")]
+    InvalidLegacyEscrow,
+    #[msg("Arbitrated escrows are only refunded through the arbiter")]
+    ArbitratedEscrow,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn offer(amount_to_send: u64, amount_to_receive: u64) -> EscrowAccount {
+        EscrowAccount {
+            initializer: Pubkey::new_unique(),
+            offer_id: 0,
+            initializer_token_account: Pubkey::new_unique(),
+            amount_to_send,
+            amount_to_receive,
+            mint: Pubkey::new_unique(),
+            escrow_bump: 255,
+            vault_bump: 255,
+            is_completed: false,
+            expires_at: None,
+            remaining_to_send: amount_to_send,
+            payment_mint: None,
+            allowed_taker: None,
+            payouts: Vec::new(),
+            dutch_auction: None,
+            mode: EscrowMode::Offer,
+            arbiter: None,
+            disputed: false,
+        }
+    }
+
+    fn split(bps: u16) -> PayoutSplit {
+        PayoutSplit {
+            recipient: Pubkey::new_unique(),
+            bps,
+        }
+    }
+
+    #[test]
+    fn price_for_rounds_partial_fills_up() {
+        let escrow = offer(3, 10);
+
+        assert_eq!(escrow.price_for(1, 0).unwrap(), 4);
+        assert_eq!(escrow.price_for(2, 0).unwrap(), 7);
+        assert_eq!(escrow.price_for(3, 0).unwrap(), 10);
+    }
+
+    #[test]
+    fn fee_for_rounds_down() {
+        let config = Config {
+            admin: Pubkey::new_unique(),
+            treasury: Pubkey::new_unique(),
+            fee_bps: 250,
+            bump: 255,
+            paused: false,
+            pending_admin: None,
+        };
+
+        assert_eq!(config.fee_for(10_000).unwrap(), 250);
+        assert_eq!(config.fee_for(399).unwrap(), 9);
+        assert_eq!(config.fee_for(39).unwrap(), 0);
+        assert_eq!(config.fee_for(u64::MAX).unwrap(), u64::MAX / 40);
+    }
+
+    #[test]
+    fn split_payout_gives_dust_to_last_split() {
+        let mut escrow = offer(1, 1);
+        escrow.payouts = vec![split(3_333), split(3_333), split(3_334)];
+
+        assert_eq!(escrow.split_payout(100), vec![33, 33, 34]);
+        assert_eq!(escrow.split_payout(1), vec![0, 0, 1]);
+        assert_eq!(escrow.split_payout(0), vec![0, 0, 0]);
+    }
+
+    #[test]
+    fn price_for_follows_the_dutch_auction() {
+        let mut escrow = offer(100, 0);
+        escrow.dutch_auction = Some(DutchAuction {
+            start_price: 1_000,
+            end_price: 500,
+            start_ts: 0,
+            end_ts: 100,
+            step_interval: 0,
+        });
+
+        assert_eq!(escrow.price_for(50, 0).unwrap(), 500);
+        assert_eq!(escrow.price_for(50, 50).unwrap(), 375);
+        assert_eq!(escrow.price_for(50, 100).unwrap(), 250);
+    }
+
+    #[test]
+    fn dutch_auction_decays_in_steps() {
+        let auction = DutchAuction {
+            start_price: 1_000,
+            end_price: 0,
+            start_ts: 100,
+            end_ts: 200,
+            step_interval: 10,
+        };
+
+        assert_eq!(auction.price_at(50), 1_000);
+        assert_eq!(auction.price_at(100), 1_000);
+        assert_eq!(auction.price_at(109), 1_000);
+        assert_eq!(auction.price_at(110), 900);
+        assert_eq!(auction.price_at(119), 900);
+        assert_eq!(auction.price_at(190), 100);
+        assert_eq!(auction.price_at(199), 100);
+        assert_eq!(auction.price_at(200), 0);
+        assert_eq!(auction.price_at(300), 0);
+    }
+
+    #[test]
+    fn clearing_price_falls_back_to_reserve() {
+        let mut auction = SealedBidAuction {
+            reserve_price: 500,
+            commit_end_ts: 0,
+            reveal_end_ts: 0,
+            second_price: true,
+            bid_count: 1,
+            highest_bidder: Some(Pubkey::new_unique()),
+            highest_bid: 900,
+            second_bid: 0,
+        };
+
+        assert_eq!(auction.clearing_price(), 500);
+
+        auction.second_bid = 700;
+        assert_eq!(auction.clearing_price(), 700);
+
+        auction.second_price = false;
+        assert_eq!(auction.clearing_price(), 900);
+    }
+
+    #[test]
+    fn taker_bps_covers_every_resolution() {
+        assert_eq!(DisputeResolution::Release.taker_bps(), 10_000);
+        assert_eq!(DisputeResolution::Refund.taker_bps(), 0);
+        assert_eq!(DisputeResolution::Split { taker_bps: 4_000 }.taker_bps(), 4_000);
+    }
+
+    #[test]
+    fn vesting_respects_cliff_and_revoke() {
+        let mut vesting = Vesting {
+            beneficiary: Pubkey::new_unique(),
+            start_ts: 0,
+            cliff_ts: Some(25),
+            end_ts: 100,
+            revocable: true,
+            claimed: 0,
+            revoked_at: None,
+        };
+
+        assert_eq!(vesting.vested_amount(1_000, 24), 0);
+        assert_eq!(vesting.vested_amount(1_000, 25), 250);
+        assert_eq!(vesting.vested_amount(1_000, 99), 990);
+        assert_eq!(vesting.vested_amount(1_000, 100), 1_000);
+
+        vesting.revoked_at = Some(60);
+        assert_eq!(vesting.vested_amount(1_000, 60), 600);
+        assert_eq!(vesting.vested_amount(1_000, 200), 600);
+
+        vesting.revoked_at = Some(10);
+        assert_eq!(vesting.vested_amount(1_000, 200), 0);
+    }
+
+    #[test]
+    fn vesting_without_cliff_starts_at_start() {
+        let vesting = Vesting {
+            beneficiary: Pubkey::new_unique(),
+            start_ts: 10,
+            cliff_ts: None,
+            end_ts: 13,
+            revocable: false,
+            claimed: 0,
+            revoked_at: None,
+        };
+
+        assert_eq!(vesting.vested_amount(100, 9), 0);
+        assert_eq!(vesting.vested_amount(100, 10), 0);
+        assert_eq!(vesting.vested_amount(100, 11), 33);
+        assert_eq!(vesting.vested_amount(100, 12), 66);
+    }
+
+    #[test]
+    fn space_fits_escrows_with_every_option_set() {
+        let mut escrow = offer(1, 1);
+        escrow.expires_at = Some(0);
+        escrow.payment_mint = Some(Pubkey::new_unique());
+        escrow.allowed_taker = Some(Pubkey::new_unique());
+        escrow.dutch_auction = Some(DutchAuction {
+            start_price: 1,
+            end_price: 0,
+            start_ts: 0,
+            end_ts: 1,
+            step_interval: 0,
+        });
+        escrow.arbiter = Some(Pubkey::new_unique());
+        assert_eq!(borsh::to_vec(&escrow).unwrap().len(), EscrowAccount::BASE_SPACE);
+
+        escrow.payouts = vec![split(5_000), split(5_000)];
+        escrow.mode = EscrowMode::Multisig(Multisig {
+            beneficiary: Pubkey::new_unique(),
+            approvers: vec![Pubkey::new_unique(); 3],
+            threshold: 2,
+            release_approvals: 0,
+            refund_approvals: 0,
+        });
+        assert_eq!(borsh::to_vec(&escrow).unwrap().len(), escrow.space());
+
+        escrow.mode = EscrowMode::Milestones(MilestonePlan {
+            beneficiary: Pubkey::new_unique(),
+            approver: Some(Pubkey::new_unique()),
+            milestones: vec![
+                Milestone {
+                    amount: 1,
+                    description_hash: [0; 32],
+                };
+                2
+            ],
+            released: 0,
+        });
+        assert_eq!(borsh::to_vec(&escrow).unwrap().len(), escrow.space());
+        assert!(escrow.space() < EscrowAccount::INIT_SPACE);
+    }
+}
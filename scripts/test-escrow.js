@@ -49,17 +49,31 @@ async function main() {
     await provider.sendAndConfirm(tx, [bob]);
     console.log("✅ Bob's token account:", bobTokenAccount.toBase58());
 
-    // Derive PDAs
+    // Derive PDAs (one escrow per Alice + offer id)
+    const offerId = new anchor.BN(1);
+    const offerIdSeed = offerId.toArrayLike(Buffer, "le", 8);
+
     const [escrowAccount] = PublicKey.findProgramAddressSync(
-        [Buffer.from("escrow"), alice.publicKey.toBuffer()],
+        [Buffer.from("escrow"), alice.publicKey.toBuffer(), offerIdSeed],
         program.programId
     );
 
     const [vault] = PublicKey.findProgramAddressSync(
-        [Buffer.from("vault"), alice.publicKey.toBuffer()],
+        [Buffer.from("vault"), alice.publicKey.toBuffer(), offerIdSeed],
         program.programId
     );
 
+    // Global config (created once by the upgrade authority with initializeConfig)
+    const [config] = PublicKey.findProgramAddressSync(
+        [Buffer.from("config")],
+        program.programId
+    );
+    const { treasury, feeBps, paused } = await program.account.config.fetch(config);
+    if (paused) {
+        throw new Error("Program is paused, new escrows and fills are disabled");
+    }
+    console.log("\n🏛️  Protocol fee:", feeBps, "bps to", treasury.toBase58());
+
     // Escrow terms
     const amountToSend = new anchor.BN(50_000_000); // 50 DED tokens (6 decimals)
     const amountToReceive = new anchor.BN(0.01 * LAMPORTS_PER_SOL); // 0.01 SOL
@@ -80,6 +94,7 @@ async function main() {
                 initializerTokenAccount: aliceTokenAccount,
                 vault: vault,
                 escrowAccount: escrowAccount,
+                mint: DED_MINT,
                 tokenProgram: TOKEN_PROGRAM_ID,
             })
             .rpc();
@@ -94,13 +109,15 @@ async function main() {
     // 1. Initialize Escrow
     console.log("\n🔒 Step 1: Alice initializes escrow...");
     const initTx = await program.methods
-        .initializeEscrow(amountToSend, amountToReceive)
+        .initializeEscrow(offerId, amountToSend, amountToReceive, null, bob.publicKey, [], null, null)
         .accounts({
             initializer: alice.publicKey,
             mint: DED_MINT,
             initializerTokenAccount: aliceTokenAccount,
+            paymentMint: null, // paid in SOL
             escrowAccount: escrowAccount,
             vault: vault,
+            config: config,
             tokenProgram: TOKEN_PROGRAM_ID,
             systemProgram: SystemProgram.programId,
         })
@@ -118,11 +135,19 @@ async function main() {
     // 2. Bob completes the exchange
     console.log("\n💱 Step 2: Bob completes the exchange...");
     const exchangeTx = await program.methods
-        .exchange()
+        .exchange(amountToSend, amountToReceive, amountToSend, DED_MINT) // fill the whole offer at the agreed terms
         .accounts({
             taker: bob.publicKey,
             initializer: alice.publicKey,
             takerTokenAccount: bobTokenAccount,
+            takerPaymentAccount: null,
+            initializerPaymentAccount: null,
+            paymentMint: null,
+            paymentTokenProgram: null,
+            unwrapAccount: null, // only needed when paying a SOL escrow in wSOL
+            config: config,
+            treasury: treasury,
+            treasuryPaymentAccount: null,
             vault: vault,
             escrowAccount: escrowAccount,
             mint: DED_MINT,
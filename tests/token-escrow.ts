@@ -1,16 +1,1404 @@
 import * as anchor from "@coral-xyz/anchor";
-import { Program } from "@coral-xyz/anchor";
+import { Program, BN } from "@coral-xyz/anchor";
+import { Keypair, LAMPORTS_PER_SOL, PublicKey, SystemProgram, Transaction } from "@solana/web3.js";
+import {
+  ExtensionType,
+  NATIVE_MINT,
+  TOKEN_2022_PROGRAM_ID,
+  TOKEN_PROGRAM_ID,
+  createAssociatedTokenAccount,
+  createInitializeMintInstruction,
+  createInitializeTransferFeeConfigInstruction,
+  createMint,
+  createWrappedNativeAccount,
+  getAccount,
+  getMint,
+  getMintLen,
+  getTransferFeeConfig,
+  mintTo,
+} from "@solana/spl-token";
+import { expect } from "chai";
+import { createHash } from "crypto";
+import fs from "fs";
+import path from "path";
 import { TokenEscrow } from "../target/types/token_escrow";
 
+const BPF_LOADER_UPGRADEABLE_ID = new PublicKey("BPFLoaderUpgradeab1e11111111111111111111111");
+
 describe("token-escrow", () => {
   // Configure the client to use the local cluster.
-  anchor.setProvider(anchor.AnchorProvider.env());
+  const provider = anchor.AnchorProvider.env();
+  anchor.setProvider(provider);
 
   const program = anchor.workspace.tokenEscrow as Program<TokenEscrow>;
+  const connection = provider.connection;
+  const payer = (provider.wallet as anchor.Wallet).payer;
+
+  // Alice sells DED tokens, Bob buys them. The provider wallet pays all
+  // transaction fees, so their SOL balances only move with the escrow.
+  const alice = Keypair.generate();
+  const bob = Keypair.generate();
+  const treasury = Keypair.generate();
+  const arbiter = Keypair.generate();
+  const carol = Keypair.generate();
+
+  const feeBps = 100; // 1%
+
+  // A mint with Alice's and Bob's token accounts for it
+  type Tokens = {
+    mint: PublicKey;
+    tokenProgram: PublicKey;
+    aliceTokenAccount: PublicKey;
+    bobTokenAccount: PublicKey;
+  };
+
+  // Token an escrow is paid in, with the treasury's account for the protocol fee
+  type Payment = Tokens & { treasuryTokenAccount: PublicKey };
+
+  // Optional escrow terms, everything defaults to a public SOL-priced DED offer
+  type EscrowTerms = {
+    tokens?: Tokens;
+    payment?: Payment | null;
+    expiresAt?: BN | null;
+    allowedTaker?: PublicKey | null;
+    payouts?: { recipient: PublicKey; bps: number }[];
+    arbiter?: PublicKey | null;
+  };
+
+  let ded: Tokens;
+  let mint: PublicKey;
+  let aliceTokenAccount: PublicKey;
+  let bobTokenAccount: PublicKey;
+  let carolTokenAccount: PublicKey;
+
+  const [config] = PublicKey.findProgramAddressSync([Buffer.from("config")], program.programId);
+
+  const escrowPdas = (offerId: BN) => {
+    const offerIdSeed = offerId.toArrayLike(Buffer, "le", 8);
+    const [escrowAccount] = PublicKey.findProgramAddressSync(
+      [Buffer.from("escrow"), alice.publicKey.toBuffer(), offerIdSeed],
+      program.programId
+    );
+    const [vault] = PublicKey.findProgramAddressSync(
+      [Buffer.from("vault"), alice.publicKey.toBuffer(), offerIdSeed],
+      program.programId
+    );
+    return { escrowAccount, vault };
+  };
+
+  const auctionBidPda = (escrowAccount: PublicKey, bidder: PublicKey) =>
+    PublicKey.findProgramAddressSync(
+      [Buffer.from("auction_bid"), escrowAccount.toBuffer(), bidder.toBuffer()],
+      program.programId
+    )[0];
+
+  const sleep = (ms: number) => new Promise((resolve) => setTimeout(resolve, ms));
+
+  // Unix timestamp of the validator's clock
+  const chainTime = async () => (await connection.getBlockTime(await connection.getSlot()))!;
+
+  const waitUntil = async (timestamp: number) => {
+    while ((await chainTime()) <= timestamp) {
+      await sleep(500);
+    }
+  };
+
+  // Fails unless `tx` is rejected with the program error `code`
+  const expectError = async (tx: Promise<unknown>, code: string) => {
+    try {
+      await tx;
+      expect.fail(`should have failed with ${code}`);
+    } catch (err) {
+      expect((err as anchor.AnchorError).error.errorCode.code).to.equal(code);
+    }
+  };
+
+  const fund = async (to: PublicKey, lamports: number) => {
+    const tx = new anchor.web3.Transaction().add(
+      SystemProgram.transfer({ fromPubkey: payer.publicKey, toPubkey: to, lamports })
+    );
+    await provider.sendAndConfirm(tx);
+  };
+
+  const tokenBalance = async (tokenAccount: PublicKey, tokenProgram = TOKEN_PROGRAM_ID) =>
+    Number((await getAccount(connection, tokenAccount, undefined, tokenProgram)).amount);
+
+  const setupTokens = async (mint: PublicKey, tokenProgram: PublicKey): Promise<Tokens> => {
+    const aliceTokenAccount = await createAssociatedTokenAccount(
+      connection,
+      payer,
+      mint,
+      alice.publicKey,
+      undefined,
+      tokenProgram
+    );
+    const bobTokenAccount = await createAssociatedTokenAccount(
+      connection,
+      payer,
+      mint,
+      bob.publicKey,
+      undefined,
+      tokenProgram
+    );
+    await mintTo(connection, payer, mint, aliceTokenAccount, payer, 1_000_000_000, [], undefined, tokenProgram);
+    return { mint, tokenProgram, aliceTokenAccount, bobTokenAccount };
+  };
+
+  // Token-2022 mint charging `transferFeeBps` on every transfer
+  const createTransferFeeMint = async (transferFeeBps: number) => {
+    const mintKeypair = Keypair.generate();
+    const mintLen = getMintLen([ExtensionType.TransferFeeConfig]);
+    const tx = new Transaction().add(
+      SystemProgram.createAccount({
+        fromPubkey: payer.publicKey,
+        newAccountPubkey: mintKeypair.publicKey,
+        space: mintLen,
+        lamports: await connection.getMinimumBalanceForRentExemption(mintLen),
+        programId: TOKEN_2022_PROGRAM_ID,
+      }),
+      createInitializeTransferFeeConfigInstruction(
+        mintKeypair.publicKey,
+        payer.publicKey,
+        payer.publicKey,
+        transferFeeBps,
+        BigInt(1_000_000_000),
+        TOKEN_2022_PROGRAM_ID
+      ),
+      createInitializeMintInstruction(mintKeypair.publicKey, 6, payer.publicKey, null, TOKEN_2022_PROGRAM_ID)
+    );
+    await provider.sendAndConfirm(tx, [mintKeypair]);
+    return mintKeypair.publicKey;
+  };
+
+  const initializeEscrow = async (
+    offerId: BN,
+    amountToSend: BN,
+    amountToReceive: BN,
+    {
+      tokens = ded,
+      payment = null,
+      expiresAt = null,
+      allowedTaker = null,
+      payouts = [],
+      arbiter = null,
+    }: EscrowTerms = {}
+  ) => {
+    const { escrowAccount, vault } = escrowPdas(offerId);
+    await program.methods
+      .initializeEscrow(offerId, amountToSend, amountToReceive, expiresAt, allowedTaker, payouts, null, arbiter)
+      .accountsPartial({
+        initializer: alice.publicKey,
+        mint: tokens.mint,
+        initializerTokenAccount: tokens.aliceTokenAccount,
+        paymentMint: payment?.mint ?? null, // null means paid in SOL
+        escrowAccount,
+        vault,
+        config,
+        tokenProgram: tokens.tokenProgram,
+        systemProgram: SystemProgram.programId,
+      })
+      .signers([alice])
+      .rpc();
+  };
+
+  const exchange = (
+    offerId: BN,
+    fillAmount: BN,
+    maxPayment: BN,
+    {
+      tokens = ded,
+      payment = null,
+      minReceive = fillAmount,
+      taker = bob,
+      takerTokenAccount = tokens.bobTokenAccount,
+      payoutAccounts = [],
+    }: {
+      tokens?: Tokens;
+      payment?: Payment | null;
+      minReceive?: BN;
+      taker?: Keypair;
+      takerTokenAccount?: PublicKey;
+      payoutAccounts?: PublicKey[];
+    } = {}
+  ) => {
+    const { escrowAccount, vault } = escrowPdas(offerId);
+    return program.methods
+      .exchange(fillAmount, maxPayment, minReceive, tokens.mint)
+      .accountsPartial({
+        taker: taker.publicKey,
+        initializer: alice.publicKey,
+        takerTokenAccount,
+        takerPaymentAccount: payment?.bobTokenAccount ?? null,
+        initializerPaymentAccount: payment?.aliceTokenAccount ?? null,
+        paymentMint: payment?.mint ?? null,
+        paymentTokenProgram: payment?.tokenProgram ?? null,
+        unwrapAccount: null,
+        config,
+        treasury: treasury.publicKey,
+        treasuryPaymentAccount: payment?.treasuryTokenAccount ?? null,
+        vault,
+        escrowAccount,
+        mint: tokens.mint,
+        tokenProgram: tokens.tokenProgram,
+        systemProgram: SystemProgram.programId,
+      })
+      .remainingAccounts(payoutAccounts.map((pubkey) => ({ pubkey, isSigner: false, isWritable: true })))
+      .signers([taker])
+      .rpc();
+  };
+
+  const cancel = (offerId: BN, tokens = ded) => {
+    const { escrowAccount, vault } = escrowPdas(offerId);
+    return program.methods
+      .cancel()
+      .accountsPartial({
+        initializer: alice.publicKey,
+        initializerTokenAccount: tokens.aliceTokenAccount,
+        vault,
+        escrowAccount,
+        mint: tokens.mint,
+        tokenProgram: tokens.tokenProgram,
+      })
+      .signers([alice])
+      .rpc();
+  };
+
+  before(async () => {
+    await fund(alice.publicKey, LAMPORTS_PER_SOL);
+    await fund(bob.publicKey, 10 * LAMPORTS_PER_SOL);
+    await fund(carol.publicKey, 2 * LAMPORTS_PER_SOL);
+    // Keeps the treasury rent exempt when small fees land on it
+    await fund(treasury.publicKey, LAMPORTS_PER_SOL);
+
+    ded = await setupTokens(await createMint(connection, payer, payer.publicKey, null, 6), TOKEN_PROGRAM_ID);
+    ({ mint, aliceTokenAccount, bobTokenAccount } = ded);
+    carolTokenAccount = await createAssociatedTokenAccount(connection, payer, mint, carol.publicKey);
+
+    // The test validator deploys the program with the provider wallet as upgrade authority
+    const [programData] = PublicKey.findProgramAddressSync(
+      [program.programId.toBuffer()],
+      BPF_LOADER_UPGRADEABLE_ID
+    );
+    await program.methods
+      .initializeConfig(feeBps, treasury.publicKey)
+      .accountsPartial({
+        admin: payer.publicKey,
+        config,
+        escrowProgram: program.programId,
+        programData,
+        systemProgram: SystemProgram.programId,
+      })
+      .rpc();
+  });
+
+  it("fills an offer partially, then completes it", async () => {
+    const offerId = new BN(1);
+    const { escrowAccount, vault } = escrowPdas(offerId);
+
+    // 100 DED for 1 SOL
+    await initializeEscrow(offerId, new BN(100_000_000), new BN(LAMPORTS_PER_SOL));
+    expect(await tokenBalance(vault)).to.equal(100_000_000);
+
+    // Bob takes 30 DED for 0.3 SOL, 1% of it goes to the treasury
+    const aliceBefore = await connection.getBalance(alice.publicKey);
+    const treasuryBefore = await connection.getBalance(treasury.publicKey);
+    await exchange(offerId, new BN(30_000_000), new BN(300_000_000));
+
+    expect(await tokenBalance(bobTokenAccount)).to.equal(30_000_000);
+    expect(await tokenBalance(vault)).to.equal(70_000_000);
+    expect((await connection.getBalance(alice.publicKey)) - aliceBefore).to.equal(297_000_000);
+    expect((await connection.getBalance(treasury.publicKey)) - treasuryBefore).to.equal(3_000_000);
+
+    const escrow = await program.account.escrowAccount.fetch(escrowAccount);
+    expect(escrow.remainingToSend.toNumber()).to.equal(70_000_000);
+
+    // Bob won't pay more than the pro-rata price
+    try {
+      await exchange(offerId, new BN(70_000_000), new BN(699_999_999));
+      expect.fail("exchange should have failed");
+    } catch (err) {
+      expect((err as anchor.AnchorError).error.errorCode.code).to.equal("SlippageExceeded");
+    }
+
+    // Filling the rest closes the vault and the escrow
+    await exchange(offerId, new BN(70_000_000), new BN(700_000_000));
+
+    expect(await tokenBalance(bobTokenAccount)).to.equal(100_000_000);
+    expect(await connection.getAccountInfo(vault)).to.be.null;
+    expect(await connection.getAccountInfo(escrowAccount)).to.be.null;
+  });
+
+  it("cancels an offer and returns the tokens", async () => {
+    const offerId = new BN(2);
+    const { escrowAccount, vault } = escrowPdas(offerId);
+
+    const aliceTokensBefore = await tokenBalance(aliceTokenAccount);
+    await initializeEscrow(offerId, new BN(50_000_000), new BN(LAMPORTS_PER_SOL));
+    expect(await tokenBalance(aliceTokenAccount)).to.equal(aliceTokensBefore - 50_000_000);
+
+    await cancel(offerId);
+
+    expect(await tokenBalance(aliceTokenAccount)).to.equal(aliceTokensBefore);
+    expect(await connection.getAccountInfo(vault)).to.be.null;
+    expect(await connection.getAccountInfo(escrowAccount)).to.be.null;
+  });
+
+  it("refunds an arbitrated escrow only through the arbiter", async () => {
+    const offerId = new BN(3);
+    const { escrowAccount, vault } = escrowPdas(offerId);
+
+    const aliceTokensBefore = await tokenBalance(aliceTokenAccount);
+    await initializeEscrow(offerId, new BN(50_000_000), new BN(LAMPORTS_PER_SOL), {
+      allowedTaker: bob.publicKey,
+      arbiter: arbiter.publicKey,
+    });
+
+    // Alice can't pull the tokens out from under Bob
+    try {
+      await cancel(offerId);
+      expect.fail("cancel should have failed");
+    } catch (err) {
+      expect((err as anchor.AnchorError).error.errorCode.code).to.equal("ArbitratedEscrow");
+    }
+
+    try {
+      await program.methods
+        .updateEscrow(null, new BN(0), new BN(10_000_000))
+        .accountsPartial({
+          initializer: alice.publicKey,
+          initializerTokenAccount: aliceTokenAccount,
+          vault,
+          escrowAccount,
+          mint,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .signers([alice])
+        .rpc();
+      expect.fail("withdrawal should have failed");
+    } catch (err) {
+      expect((err as anchor.AnchorError).error.errorCode.code).to.equal("ArbitratedEscrow");
+    }
+
+    // She gets a refund by raising a dispute for the arbiter to resolve
+    await program.methods
+      .raiseDispute()
+      .accountsPartial({ party: alice.publicKey, escrowAccount })
+      .signers([alice])
+      .rpc();
+
+    await program.methods
+      .resolveDispute({ refund: {} })
+      .accountsPartial({
+        arbiter: arbiter.publicKey,
+        initializer: alice.publicKey,
+        initializerTokenAccount: aliceTokenAccount,
+        takerTokenAccount: bobTokenAccount,
+        vault,
+        escrowAccount,
+        mint,
+        tokenProgram: TOKEN_PROGRAM_ID,
+      })
+      .signers([arbiter])
+      .rpc();
+
+    expect(await tokenBalance(aliceTokenAccount)).to.equal(aliceTokensBefore);
+    expect(await connection.getAccountInfo(vault)).to.be.null;
+    expect(await connection.getAccountInfo(escrowAccount)).to.be.null;
+  });
+
+  it("harvests withheld transfer fees before closing the vault", async () => {
+    const offerId = new BN(4);
+    const { escrowAccount, vault } = escrowPdas(offerId);
+
+    // Every transfer of this mint withholds 1% on the receiving account
+    const feeTokens = await setupTokens(await createTransferFeeMint(100), TOKEN_2022_PROGRAM_ID);
+    const aliceTokensBefore = await tokenBalance(feeTokens.aliceTokenAccount, TOKEN_2022_PROGRAM_ID);
+
+    // Only 99 of the 100 tokens sent land in the vault, the escrow prices those
+    await initializeEscrow(offerId, new BN(100_000_000), new BN(LAMPORTS_PER_SOL), { tokens: feeTokens });
+    const escrow = await program.account.escrowAccount.fetch(escrowAccount);
+    expect(escrow.amountToSend.toNumber()).to.equal(99_000_000);
+
+    // Bob takes half and pays for the 49.005 tokens he receives after the fee
+    await exchange(offerId, new BN(49_500_000), new BN(495_000_000), {
+      tokens: feeTokens,
+      minReceive: new BN(49_005_000),
+    });
+    expect(await tokenBalance(feeTokens.bobTokenAccount, TOKEN_2022_PROGRAM_ID)).to.equal(49_005_000);
+
+    // The vault still holds the fee withheld on deposit, cancel moves it to the mint and closes the vault
+    await cancel(offerId, feeTokens);
+
+    expect(await tokenBalance(feeTokens.aliceTokenAccount, TOKEN_2022_PROGRAM_ID)).to.equal(
+      aliceTokensBefore - 100_000_000 + 49_005_000
+    );
+    expect(await connection.getAccountInfo(vault)).to.be.null;
+    expect(await connection.getAccountInfo(escrowAccount)).to.be.null;
+
+    const feeMint = await getMint(connection, feeTokens.mint, undefined, TOKEN_2022_PROGRAM_ID);
+    expect(Number(getTransferFeeConfig(feeMint)!.withheldAmount)).to.equal(1_000_000);
+  });
+
+  it("runs an English auction with outbid refunds and bid raises", async () => {
+    const offerId = new BN(5);
+    const { escrowAccount, vault } = escrowPdas(offerId);
+    const bobBid = auctionBidPda(escrowAccount, bob.publicKey);
+    const carolBid = auctionBidPda(escrowAccount, carol.publicKey);
+
+    // 10 DED, opening at 0.1 SOL with 0.01 SOL increments
+    const endTs = (await chainTime()) + 8;
+    await program.methods
+      .initializeEnglishAuction(offerId, new BN(10_000_000), new BN(100_000_000), new BN(10_000_000), new BN(endTs))
+      .accountsPartial({
+        initializer: alice.publicKey,
+        mint,
+        initializerTokenAccount: aliceTokenAccount,
+        paymentMint: null,
+        escrowAccount,
+        vault,
+        config,
+        tokenProgram: TOKEN_PROGRAM_ID,
+        systemProgram: SystemProgram.programId,
+      })
+      .signers([alice])
+      .rpc();
+
+    const placeBid = (bidder: Keypair, amount: number, previousBidder: PublicKey | null = null) =>
+      program.methods
+        .placeBid(new BN(amount))
+        .accountsPartial({
+          bidder: bidder.publicKey,
+          auctionBid: auctionBidPda(escrowAccount, bidder.publicKey),
+          previousBid: previousBidder && auctionBidPda(escrowAccount, previousBidder),
+          previousBidder,
+          escrowAccount,
+          config,
+          systemProgram: SystemProgram.programId,
+        })
+        .signers([bidder])
+        .rpc();
+
+    const expectBidTooLow = async (bid: Promise<string>) => {
+      try {
+        await bid;
+        expect.fail("bid should have failed");
+      } catch (err) {
+        expect((err as anchor.AnchorError).error.errorCode.code).to.equal("BidTooLow");
+      }
+    };
+
+    // Bids start at the reserve
+    await expectBidTooLow(placeBid(bob, 50_000_000));
+    await placeBid(bob, 100_000_000);
+
+    // Carol has to beat Bob by the increment, Bob gets his bid back when she does
+    await expectBidTooLow(placeBid(carol, 105_000_000, bob.publicKey));
+    const bobBefore = await connection.getBalance(bob.publicKey);
+    const bobBidRent = await connection.getBalance(bobBid);
+    await placeBid(carol, 200_000_000, bob.publicKey);
+
+    expect(await connection.getAccountInfo(bobBid)).to.be.null;
+    expect((await connection.getBalance(bob.publicKey)) - bobBefore).to.equal(bobBidRent);
+
+    // Carol raises her own bid, only the difference is locked
+    const carolBidBefore = await connection.getBalance(carolBid);
+    await placeBid(carol, 300_000_000);
+    expect((await connection.getBalance(carolBid)) - carolBidBefore).to.equal(100_000_000);
+
+    const auction = (await program.account.escrowAccount.fetch(escrowAccount)).mode.englishAuction![0];
+    expect(auction.highestBidder!.toBase58()).to.equal(carol.publicKey.toBase58());
+    expect(auction.highestBid.toNumber()).to.equal(300_000_000);
+
+    const settle = () =>
+      program.methods
+        .settleAuction()
+        .accountsPartial({
+          caller: payer.publicKey,
+          initializer: alice.publicKey,
+          initializerTokenAccount: aliceTokenAccount,
+          winner: carol.publicKey,
+          winnerTokenAccount: carolTokenAccount,
+          winningBid: carolBid,
+          config,
+          treasury: treasury.publicKey,
+          vault,
+          escrowAccount,
+          mint,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .rpc();
+
+    try {
+      await settle();
+      expect.fail("settle should have failed");
+    } catch (err) {
+      expect((err as anchor.AnchorError).error.errorCode.code).to.equal("AuctionNotEnded");
+    }
+
+    // Once the auction ends Carol gets the tokens and Alice the winning bid minus the fee
+    await waitUntil(endTs);
+    const aliceBefore = await connection.getBalance(alice.publicKey);
+    await settle();
+
+    expect(await tokenBalance(carolTokenAccount)).to.equal(10_000_000);
+    expect((await connection.getBalance(alice.publicKey)) - aliceBefore).to.be.at.least(297_000_000);
+    expect(await connection.getAccountInfo(carolBid)).to.be.null;
+    expect(await connection.getAccountInfo(escrowAccount)).to.be.null;
+  });
+
+  it("lets anyone reclaim an expired offer for Alice", async () => {
+    const offerId = new BN(6);
+    const { escrowAccount, vault } = escrowPdas(offerId);
+
+    const aliceTokensBefore = await tokenBalance(aliceTokenAccount);
+    const expiresAt = (await chainTime()) + 4;
+    await initializeEscrow(offerId, new BN(20_000_000), new BN(LAMPORTS_PER_SOL), {
+      expiresAt: new BN(expiresAt),
+    });
+
+    const reclaim = () =>
+      program.methods
+        .reclaimExpired()
+        .accountsPartial({
+          caller: payer.publicKey,
+          initializer: alice.publicKey,
+          initializerTokenAccount: aliceTokenAccount,
+          vault,
+          escrowAccount,
+          mint,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .rpc();
+
+    await expectError(reclaim(), "NotExpired");
+
+    // Past the expiry Bob can't fill it anymore, but anyone can send the tokens back
+    await waitUntil(expiresAt);
+    await expectError(exchange(offerId, new BN(10_000_000), new BN(LAMPORTS_PER_SOL)), "Expired");
+    await reclaim();
+
+    expect(await tokenBalance(aliceTokenAccount)).to.equal(aliceTokensBefore);
+    expect(await connection.getAccountInfo(vault)).to.be.null;
+    expect(await connection.getAccountInfo(escrowAccount)).to.be.null;
+  });
+
+  it("sells for an SPL token instead of SOL", async () => {
+    const offerId = new BN(7);
+    const { escrowAccount, vault } = escrowPdas(offerId);
+
+    // Bob pays in USDC, the treasury takes its fee in USDC too
+    const usdcMint = await createMint(connection, payer, payer.publicKey, null, 6);
+    const usdc: Payment = {
+      mint: usdcMint,
+      tokenProgram: TOKEN_PROGRAM_ID,
+      aliceTokenAccount: await createAssociatedTokenAccount(connection, payer, usdcMint, alice.publicKey),
+      bobTokenAccount: await createAssociatedTokenAccount(connection, payer, usdcMint, bob.publicKey),
+      treasuryTokenAccount: await createAssociatedTokenAccount(connection, payer, usdcMint, treasury.publicKey),
+    };
+    await mintTo(connection, payer, usdcMint, usdc.bobTokenAccount, payer, 100_000_000);
+
+    // 10 DED for 50 USDC
+    await initializeEscrow(offerId, new BN(10_000_000), new BN(50_000_000), { payment: usdc });
+
+    // Paying with another mint than the escrow asks for is rejected
+    await expectError(
+      exchange(offerId, new BN(4_000_000), new BN(20_000_000), { payment: { ...usdc, mint } }),
+      "PaymentMintMismatch"
+    );
+
+    // 4 DED cost 20 USDC, 1% of it goes to the treasury
+    const bobDedBefore = await tokenBalance(bobTokenAccount);
+    await exchange(offerId, new BN(4_000_000), new BN(20_000_000), { payment: usdc });
+
+    expect(await tokenBalance(bobTokenAccount)).to.equal(bobDedBefore + 4_000_000);
+    expect(await tokenBalance(usdc.bobTokenAccount)).to.equal(80_000_000);
+    expect(await tokenBalance(usdc.aliceTokenAccount)).to.equal(19_800_000);
+    expect(await tokenBalance(usdc.treasuryTokenAccount)).to.equal(200_000);
+
+    // Taking the rest closes the escrow
+    await exchange(offerId, new BN(6_000_000), new BN(30_000_000), { payment: usdc });
+
+    expect(await tokenBalance(usdc.aliceTokenAccount)).to.equal(49_500_000);
+    expect(await connection.getAccountInfo(vault)).to.be.null;
+    expect(await connection.getAccountInfo(escrowAccount)).to.be.null;
+  });
+
+  it("escrows Token-2022 mints", async () => {
+    const offerId = new BN(8);
+    const { escrowAccount, vault } = escrowPdas(offerId);
+
+    const token2022Mint = await createMint(
+      connection,
+      payer,
+      payer.publicKey,
+      null,
+      6,
+      undefined,
+      undefined,
+      TOKEN_2022_PROGRAM_ID
+    );
+    const tokens = await setupTokens(token2022Mint, TOKEN_2022_PROGRAM_ID);
+
+    // The vault has to be created by the program owning the mint
+    await expectError(
+      initializeEscrow(offerId, new BN(10_000_000), new BN(LAMPORTS_PER_SOL), {
+        tokens: { ...tokens, tokenProgram: TOKEN_PROGRAM_ID },
+      }),
+      "ConstraintMintTokenProgram"
+    );
+
+    await initializeEscrow(offerId, new BN(10_000_000), new BN(LAMPORTS_PER_SOL), { tokens });
+    expect(await tokenBalance(vault, TOKEN_2022_PROGRAM_ID)).to.equal(10_000_000);
+
+    await exchange(offerId, new BN(10_000_000), new BN(LAMPORTS_PER_SOL), { tokens });
+
+    expect(await tokenBalance(tokens.bobTokenAccount, TOKEN_2022_PROGRAM_ID)).to.equal(10_000_000);
+    expect(await connection.getAccountInfo(vault)).to.be.null;
+    expect(await connection.getAccountInfo(escrowAccount)).to.be.null;
+  });
+
+  it("only lets the designated taker fill a private offer", async () => {
+    const offerId = new BN(9);
+    const { escrowAccount } = escrowPdas(offerId);
+
+    await initializeEscrow(offerId, new BN(10_000_000), new BN(100_000_000), { allowedTaker: bob.publicKey });
+
+    await expectError(
+      exchange(offerId, new BN(10_000_000), new BN(100_000_000), { taker: carol, takerTokenAccount: carolTokenAccount }),
+      "TakerNotAllowed"
+    );
+
+    const bobTokensBefore = await tokenBalance(bobTokenAccount);
+    await exchange(offerId, new BN(10_000_000), new BN(100_000_000));
+
+    expect(await tokenBalance(bobTokenAccount)).to.equal(bobTokensBefore + 10_000_000);
+    expect(await connection.getAccountInfo(escrowAccount)).to.be.null;
+  });
+
+  it("reprices and resizes an offer", async () => {
+    const offerId = new BN(10);
+    const { escrowAccount, vault } = escrowPdas(offerId);
+
+    const updateEscrow = (amountToReceive: BN | null, depositAmount: number, withdrawAmount: number) =>
+      program.methods
+        .updateEscrow(amountToReceive, new BN(depositAmount), new BN(withdrawAmount))
+        .accountsPartial({
+          initializer: alice.publicKey,
+          initializerTokenAccount: aliceTokenAccount,
+          vault,
+          escrowAccount,
+          mint,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .signers([alice])
+        .rpc();
+
+    // 20 DED for 0.2 SOL, Bob takes 5 of them
+    await initializeEscrow(offerId, new BN(20_000_000), new BN(200_000_000));
+    await exchange(offerId, new BN(5_000_000), new BN(50_000_000));
+
+    // The new price covers the 15 DED left
+    await updateEscrow(new BN(300_000_000), 0, 0);
+    let escrow = await program.account.escrowAccount.fetch(escrowAccount);
+    expect(escrow.amountToSend.toNumber()).to.equal(15_000_000);
+    expect(escrow.amountToReceive.toNumber()).to.equal(300_000_000);
+
+    // Withdrawing without a new price keeps the per-token price
+    await updateEscrow(null, 0, 5_000_000);
+    escrow = await program.account.escrowAccount.fetch(escrowAccount);
+    expect(escrow.remainingToSend.toNumber()).to.equal(10_000_000);
+    expect(escrow.amountToReceive.toNumber()).to.equal(200_000_000);
+    expect(await tokenBalance(vault)).to.equal(10_000_000);
+
+    // Emptying the vault is what cancel is for
+    await expectError(updateEscrow(null, 0, 10_000_000), "InvalidAmount");
+
+    await exchange(offerId, new BN(10_000_000), new BN(200_000_000));
+    expect(await connection.getAccountInfo(escrowAccount)).to.be.null;
+  });
+
+  it("pauses new escrows and fills but not cancels, and rotates the admin", async () => {
+    const offerId = new BN(11);
+    const { escrowAccount } = escrowPdas(offerId);
+    const newAdmin = Keypair.generate();
+
+    const setPaused = (admin: Keypair, paused: boolean) =>
+      program.methods.setPaused(paused).accountsPartial({ admin: admin.publicKey, config }).signers([admin]).rpc();
+
+    await initializeEscrow(offerId, new BN(10_000_000), new BN(100_000_000));
+
+    await expectError(setPaused(alice, true), "ConstraintHasOne");
+    await setPaused(payer, true);
+
+    await expectError(initializeEscrow(new BN(12), new BN(10_000_000), new BN(100_000_000)), "Paused");
+    await expectError(exchange(offerId, new BN(10_000_000), new BN(100_000_000)), "Paused");
+
+    // Alice can still get her tokens out
+    await cancel(offerId);
+    expect(await connection.getAccountInfo(escrowAccount)).to.be.null;
+
+    await setPaused(payer, false);
+
+    // The admin role only moves once the new admin accepts it
+    const acceptAdmin = (signer: Keypair) =>
+      program.methods
+        .acceptAdmin()
+        .accountsPartial({ newAdmin: signer.publicKey, config })
+        .signers([signer])
+        .rpc();
+
+    await program.methods.transferAdmin(newAdmin.publicKey).accountsPartial({ admin: payer.publicKey, config }).rpc();
+    await expectError(acceptAdmin(bob), "Unauthorized");
+    await acceptAdmin(newAdmin);
+    expect((await program.account.config.fetch(config)).admin.toBase58()).to.equal(newAdmin.publicKey.toBase58());
+
+    // Hand it back so the provider wallet stays admin for the other tests
+    await program.methods
+      .transferAdmin(payer.publicKey)
+      .accountsPartial({ admin: newAdmin.publicKey, config })
+      .signers([newAdmin])
+      .rpc();
+    await acceptAdmin(payer);
+  });
+
+  it("splits the proceeds between payout recipients", async () => {
+    const offerId = new BN(13);
+    const { escrowAccount } = escrowPdas(offerId);
+    const partner = Keypair.generate();
+    const agent = Keypair.generate();
+
+    // 70% to the partner and 30% to the agent, nothing to Alice directly
+    await initializeEscrow(offerId, new BN(10_000_000), new BN(LAMPORTS_PER_SOL), {
+      payouts: [
+        { recipient: partner.publicKey, bps: 7_000 },
+        { recipient: agent.publicKey, bps: 3_000 },
+      ],
+    });
+
+    // The recipients are passed as remaining accounts, in order
+    await expectError(exchange(offerId, new BN(10_000_000), new BN(LAMPORTS_PER_SOL)), "InvalidPayoutAccount");
+    await expectError(
+      exchange(offerId, new BN(10_000_000), new BN(LAMPORTS_PER_SOL), {
+        payoutAccounts: [agent.publicKey, partner.publicKey],
+      }),
+      "InvalidPayoutAccount"
+    );
+
+    // Alice's 99% share is split after the 1% protocol fee
+    await exchange(offerId, new BN(10_000_000), new BN(LAMPORTS_PER_SOL), {
+      payoutAccounts: [partner.publicKey, agent.publicKey],
+    });
+
+    expect(await connection.getBalance(partner.publicKey)).to.equal(693_000_000);
+    expect(await connection.getBalance(agent.publicKey)).to.equal(297_000_000);
+    expect(await connection.getAccountInfo(escrowAccount)).to.be.null;
+  });
+
+  it("fills and cancels standing buy orders", async () => {
+    const bidPda = (offerId: BN) =>
+      PublicKey.findProgramAddressSync(
+        [Buffer.from("bid_escrow"), bob.publicKey.toBuffer(), offerId.toArrayLike(Buffer, "le", 8)],
+        program.programId
+      )[0];
+
+    const initializeBid = (offerId: BN, tokenAmount: number, lamports: number) =>
+      program.methods
+        .initializeBid(offerId, new BN(tokenAmount), new BN(lamports))
+        .accountsPartial({
+          buyer: bob.publicKey,
+          mint,
+          buyerTokenAccount: bobTokenAccount,
+          bidEscrow: bidPda(offerId),
+          config,
+          tokenProgram: TOKEN_PROGRAM_ID,
+          systemProgram: SystemProgram.programId,
+        })
+        .signers([bob])
+        .rpc();
+
+    const fillBid = (offerId: BN, minPayment: number) =>
+      program.methods
+        .fillBid(new BN(5_000_000), new BN(minPayment), mint)
+        .accountsPartial({
+          seller: alice.publicKey,
+          buyer: bob.publicKey,
+          sellerTokenAccount: aliceTokenAccount,
+          buyerTokenAccount: bobTokenAccount,
+          bidEscrow: bidPda(offerId),
+          mint,
+          config,
+          treasury: treasury.publicKey,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .signers([alice])
+        .rpc();
+
+    // Bob locks 0.5 SOL for 5 DED, Alice fills it and gets the SOL minus the 1% fee
+    const filledBid = new BN(1);
+    await initializeBid(filledBid, 5_000_000, 500_000_000);
+
+    await expectError(fillBid(filledBid, 495_000_001), "SlippageExceeded");
+
+    const aliceBefore = await connection.getBalance(alice.publicKey);
+    const bobTokensBefore = await tokenBalance(bobTokenAccount);
+    await fillBid(filledBid, 495_000_000);
+
+    expect((await connection.getBalance(alice.publicKey)) - aliceBefore).to.equal(495_000_000);
+    expect(await tokenBalance(bobTokenAccount)).to.equal(bobTokensBefore + 5_000_000);
+    expect(await connection.getAccountInfo(bidPda(filledBid))).to.be.null;
+
+    // Only Bob can take back an unfilled bid, he gets the SOL and the rent
+    const cancelledBid = new BN(2);
+    await initializeBid(cancelledBid, 5_000_000, 500_000_000);
+    const bidBalance = await connection.getBalance(bidPda(cancelledBid));
+
+    await expectError(
+      program.methods
+        .cancelBid()
+        .accountsPartial({ buyer: carol.publicKey, bidEscrow: bidPda(cancelledBid) })
+        .signers([carol])
+        .rpc(),
+      "ConstraintSeeds"
+    );
+
+    const bobBefore = await connection.getBalance(bob.publicKey);
+    await program.methods
+      .cancelBid()
+      .accountsPartial({ buyer: bob.publicKey, bidEscrow: bidPda(cancelledBid) })
+      .signers([bob])
+      .rpc();
+
+    expect((await connection.getBalance(bob.publicKey)) - bobBefore).to.equal(bidBalance);
+    expect(await connection.getAccountInfo(bidPda(cancelledBid))).to.be.null;
+  });
+
+  it("unwraps a wSOL payment so Alice still gets native SOL", async () => {
+    const offerId = new BN(14);
+    const { escrowAccount, vault } = escrowPdas(offerId);
+    const [unwrapAccount] = PublicKey.findProgramAddressSync(
+      [Buffer.from("unwrap"), escrowAccount.toBuffer()],
+      program.programId
+    );
+    const bobWsolAccount = await createWrappedNativeAccount(connection, payer, bob.publicKey, 200_000_000);
+
+    const exchangeWithWsol = (unwrap: PublicKey | null) =>
+      program.methods
+        .exchange(new BN(5_000_000), new BN(50_000_000), new BN(5_000_000), mint)
+        .accountsPartial({
+          taker: bob.publicKey,
+          initializer: alice.publicKey,
+          takerTokenAccount: bobTokenAccount,
+          takerPaymentAccount: bobWsolAccount,
+          initializerPaymentAccount: null,
+          paymentMint: NATIVE_MINT,
+          paymentTokenProgram: TOKEN_PROGRAM_ID,
+          unwrapAccount: unwrap,
+          config,
+          treasury: treasury.publicKey,
+          treasuryPaymentAccount: null,
+          vault,
+          escrowAccount,
+          mint,
+          tokenProgram: TOKEN_PROGRAM_ID,
+          systemProgram: SystemProgram.programId,
+        })
+        .signers([bob])
+        .rpc();
+
+    // 10 DED for 0.1 SOL
+    await initializeEscrow(offerId, new BN(10_000_000), new BN(100_000_000));
+
+    // Unwrapping needs the temporary account
+    await expectError(exchangeWithWsol(null), "MissingPaymentAccount");
+
+    const aliceBefore = await connection.getBalance(alice.publicKey);
+    const bobBefore = await connection.getBalance(bob.publicKey);
+    await exchangeWithWsol(unwrapAccount);
+
+    // Bob paid 0.05 SOL out of his wSOL, his SOL balance only fronted the rent and got it back
+    expect(await tokenBalance(bobWsolAccount)).to.equal(150_000_000);
+    expect(await connection.getBalance(bob.publicKey)).to.equal(bobBefore);
+    expect((await connection.getBalance(alice.publicKey)) - aliceBefore).to.equal(49_500_000);
+    expect(await connection.getAccountInfo(unwrapAccount)).to.be.null;
+    expect(await tokenBalance(vault)).to.equal(5_000_000);
+
+    await cancel(offerId);
+  });
+
+  it("runs a second-price sealed-bid auction", async () => {
+    const offerId = new BN(15);
+    const { escrowAccount, vault } = escrowPdas(offerId);
+    const sealedBidPda = (bidder: PublicKey) =>
+      PublicKey.findProgramAddressSync(
+        [Buffer.from("sealed_bid"), escrowAccount.toBuffer(), bidder.toBuffer()],
+        program.programId
+      )[0];
+    const bobSalt = Buffer.alloc(32, 1);
+    const carolSalt = Buffer.alloc(32, 2);
+
+    // sha256(amount_le || salt || bidder)
+    const commitment = (amount: number, salt: Buffer, bidder: PublicKey) =>
+      Array.from(
+        createHash("sha256")
+          .update(new BN(amount).toArrayLike(Buffer, "le", 8))
+          .update(salt)
+          .update(bidder.toBuffer())
+          .digest()
+      );
+
+    // 10 DED with a 0.1 SOL reserve, the winner pays the runner-up's bid
+    const commitEndTs = (await chainTime()) + 6;
+    const revealEndTs = commitEndTs + 6;
+    await program.methods
+      .initializeSealedAuction(
+        offerId,
+        new BN(10_000_000),
+        new BN(100_000_000),
+        new BN(commitEndTs),
+        new BN(revealEndTs),
+        true
+      )
+      .accountsPartial({
+        initializer: alice.publicKey,
+        mint,
+        initializerTokenAccount: aliceTokenAccount,
+        paymentMint: null,
+        escrowAccount,
+        vault,
+        config,
+        tokenProgram: TOKEN_PROGRAM_ID,
+        systemProgram: SystemProgram.programId,
+      })
+      .signers([alice])
+      .rpc();
+
+    const commitBid = (bidder: Keypair, amount: number, salt: Buffer, deposit: number) =>
+      program.methods
+        .commitBid(commitment(amount, salt, bidder.publicKey), new BN(deposit))
+        .accountsPartial({
+          bidder: bidder.publicKey,
+          sealedBid: sealedBidPda(bidder.publicKey),
+          escrowAccount,
+          config,
+          systemProgram: SystemProgram.programId,
+        })
+        .signers([bidder])
+        .rpc();
+
+    const revealBid = (bidder: Keypair, amount: number, salt: Buffer) =>
+      program.methods
+        .revealBid(new BN(amount), Array.from(salt))
+        .accountsPartial({ bidder: bidder.publicKey, sealedBid: sealedBidPda(bidder.publicKey), escrowAccount })
+        .signers([bidder])
+        .rpc();
+
+    // Deposits have to cover the reserve and hide the actual bids
+    await expectError(commitBid(bob, 50_000_000, bobSalt, 50_000_000), "BidTooLow");
+    await commitBid(bob, 200_000_000, bobSalt, 300_000_000);
+    await commitBid(carol, 250_000_000, carolSalt, 300_000_000);
+
+    // Nothing can be revealed before the commit phase is over
+    await expectError(revealBid(bob, 200_000_000, bobSalt), "NotRevealPhase");
+    await waitUntil(commitEndTs);
+
+    // The reveal has to match the commitment
+    await expectError(revealBid(bob, 200_000_000, carolSalt), "InvalidCommitment");
+    await revealBid(bob, 200_000_000, bobSalt);
+    await revealBid(carol, 250_000_000, carolSalt);
+
+    const auction = (await program.account.escrowAccount.fetch(escrowAccount)).mode.sealedBidAuction![0];
+    expect(auction.highestBidder!.toBase58()).to.equal(carol.publicKey.toBase58());
+    expect(auction.highestBid.toNumber()).to.equal(250_000_000);
+    expect(auction.secondBid.toNumber()).to.equal(200_000_000);
+
+    const closeBobBid = () =>
+      program.methods
+        .closeSealedBid()
+        .accountsPartial({
+          caller: payer.publicKey,
+          bidder: bob.publicKey,
+          initializer: alice.publicKey,
+          sealedBid: sealedBidPda(bob.publicKey),
+          escrowAccount,
+        })
+        .rpc();
+
+    const settle = () =>
+      program.methods
+        .settleSealedAuction()
+        .accountsPartial({
+          caller: payer.publicKey,
+          initializer: alice.publicKey,
+          initializerTokenAccount: aliceTokenAccount,
+          winner: carol.publicKey,
+          winnerTokenAccount: carolTokenAccount,
+          winningBid: sealedBidPda(carol.publicKey),
+          config,
+          treasury: treasury.publicKey,
+          vault,
+          escrowAccount,
+          mint,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .rpc();
+
+    // Neither settling nor closing bids before the reveal phase is over
+    await expectError(settle(), "AuctionNotEnded");
+    await expectError(closeBobBid(), "AuctionNotEnded");
+    await waitUntil(revealEndTs);
+
+    // Carol wins at Bob's price, 1% of it goes to the treasury
+    const carolTokensBefore = await tokenBalance(carolTokenAccount);
+    const carolBefore = await connection.getBalance(carol.publicKey);
+    const carolBidBalance = await connection.getBalance(sealedBidPda(carol.publicKey));
+    const treasuryBefore = await connection.getBalance(treasury.publicKey);
+    await settle();
+
+    expect((await tokenBalance(carolTokenAccount)) - carolTokensBefore).to.equal(10_000_000);
+    expect((await connection.getBalance(carol.publicKey)) - carolBefore).to.equal(carolBidBalance - 200_000_000);
+    expect((await connection.getBalance(treasury.publicKey)) - treasuryBefore).to.equal(2_000_000);
+    expect(await connection.getAccountInfo(vault)).to.be.null;
+
+    // Bob revealed, so his whole deposit comes back, and the escrow goes with the last bid
+    const bobBefore = await connection.getBalance(bob.publicKey);
+    const bobBidBalance = await connection.getBalance(sealedBidPda(bob.publicKey));
+    await closeBobBid();
+
+    expect((await connection.getBalance(bob.publicKey)) - bobBefore).to.equal(bobBidBalance);
+    expect(await connection.getAccountInfo(escrowAccount)).to.be.null;
+  });
+
+  it("lets only the counterparty claim an HTLC with the preimage", async () => {
+    const offerId = new BN(16);
+    const { escrowAccount, vault } = escrowPdas(offerId);
+    const preimage = Buffer.alloc(32, 7);
+    const hashlock = Array.from(createHash("sha256").update(preimage).digest());
+
+    // 10 DED for Bob, refundable to Alice after a minute
+    await program.methods
+      .initializeHtlc(offerId, new BN(10_000_000), bob.publicKey, hashlock, new BN((await chainTime()) + 60))
+      .accountsPartial({
+        initializer: alice.publicKey,
+        mint,
+        initializerTokenAccount: aliceTokenAccount,
+        paymentMint: null,
+        escrowAccount,
+        vault,
+        config,
+        tokenProgram: TOKEN_PROGRAM_ID,
+        systemProgram: SystemProgram.programId,
+      })
+      .signers([alice])
+      .rpc();
+
+    const claim = (counterparty: Keypair, counterpartyTokenAccount: PublicKey, secret: Buffer) =>
+      program.methods
+        .claimHtlc(Array.from(secret))
+        .accountsPartial({
+          counterparty: counterparty.publicKey,
+          counterpartyTokenAccount,
+          initializer: alice.publicKey,
+          vault,
+          escrowAccount,
+          mint,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .signers([counterparty])
+        .rpc();
+
+    // Alice can't take the tokens back while Bob's claim window is open
+    await expectError(cancel(offerId), "TimelockNotExpired");
+
+    // Only Bob, and only with the preimage
+    await expectError(claim(carol, carolTokenAccount, preimage), "TakerNotAllowed");
+    await expectError(claim(bob, bobTokenAccount, Buffer.alloc(32, 8)), "InvalidPreimage");
+
+    const bobTokensBefore = await tokenBalance(bobTokenAccount);
+    await claim(bob, bobTokenAccount, preimage);
+
+    expect((await tokenBalance(bobTokenAccount)) - bobTokensBefore).to.equal(10_000_000);
+    expect(await connection.getAccountInfo(vault)).to.be.null;
+    expect(await connection.getAccountInfo(escrowAccount)).to.be.null;
+  });
+
+  it("releases a multisig escrow once enough approvers agree", async () => {
+    const offerId = new BN(17);
+    const { escrowAccount, vault } = escrowPdas(offerId);
+    const release = { release: {} };
+    const refund = { refund: {} };
+
+    // 10 DED for Bob, 2 of 3 approvals to release or refund
+    await program.methods
+      .initializeMultisigEscrow(
+        offerId,
+        new BN(10_000_000),
+        bob.publicKey,
+        [bob.publicKey, carol.publicKey, arbiter.publicKey],
+        2
+      )
+      .accountsPartial({
+        initializer: alice.publicKey,
+        mint,
+        initializerTokenAccount: aliceTokenAccount,
+        paymentMint: null,
+        escrowAccount,
+        vault,
+        config,
+        tokenProgram: TOKEN_PROGRAM_ID,
+        systemProgram: SystemProgram.programId,
+      })
+      .signers([alice])
+      .rpc();
+
+    const approve = (approver: Keypair, action: typeof release | typeof refund) =>
+      program.methods
+        .approve(action)
+        .accountsPartial({ approver: approver.publicKey, escrowAccount })
+        .signers([approver])
+        .rpc();
+
+    const execute = (action: typeof release | typeof refund, recipientTokenAccount: PublicKey) =>
+      program.methods
+        .executeMultisig(action)
+        .accountsPartial({
+          caller: payer.publicKey,
+          initializer: alice.publicKey,
+          recipientTokenAccount,
+          vault,
+          escrowAccount,
+          mint,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .rpc();
+
+    // Alice isn't an approver, and nobody approves twice
+    await expectError(approve(alice, release), "Unauthorized");
+    await approve(bob, release);
+    await expectError(approve(bob, release), "AlreadyApproved");
+    await expectError(execute(release, bobTokenAccount), "ThresholdNotReached");
+
+    // Carol backs the refund first, then switches sides, so her refund vote is withdrawn
+    await approve(carol, refund);
+    await approve(arbiter, refund);
+    await approve(carol, release);
+
+    const multisig = (await program.account.escrowAccount.fetch(escrowAccount)).mode.multisig![0];
+    expect(multisig.releaseApprovals).to.equal(0b011);
+    expect(multisig.refundApprovals).to.equal(0b100);
+    await expectError(execute(refund, aliceTokenAccount), "ThresholdNotReached");
+
+    const bobTokensBefore = await tokenBalance(bobTokenAccount);
+    await execute(release, bobTokenAccount);
+
+    expect((await tokenBalance(bobTokenAccount)) - bobTokensBefore).to.equal(10_000_000);
+    expect(await connection.getAccountInfo(vault)).to.be.null;
+    expect(await connection.getAccountInfo(escrowAccount)).to.be.null;
+  });
+
+  it("releases milestones one by one", async () => {
+    const offerId = new BN(18);
+    const { escrowAccount, vault } = escrowPdas(offerId);
+
+    // 3 then 7 DED for Bob, Carol can release them besides Alice
+    await program.methods
+      .initializeMilestoneEscrow(offerId, bob.publicKey, carol.publicKey, [
+        { amount: new BN(3_000_000), descriptionHash: Array(32).fill(1) },
+        { amount: new BN(7_000_000), descriptionHash: Array(32).fill(2) },
+      ])
+      .accountsPartial({
+        initializer: alice.publicKey,
+        mint,
+        initializerTokenAccount: aliceTokenAccount,
+        paymentMint: null,
+        escrowAccount,
+        vault,
+        config,
+        tokenProgram: TOKEN_PROGRAM_ID,
+        systemProgram: SystemProgram.programId,
+      })
+      .signers([alice])
+      .rpc();
+    expect(await tokenBalance(vault)).to.equal(10_000_000);
+
+    const release = (authority: Keypair, index: number) =>
+      program.methods
+        .releaseMilestone(index)
+        .accountsPartial({
+          authority: authority.publicKey,
+          initializer: alice.publicKey,
+          beneficiaryTokenAccount: bobTokenAccount,
+          vault,
+          escrowAccount,
+          mint,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .signers([authority])
+        .rpc();
+
+    // Bob is only the beneficiary and cannot release milestones
+    await expectError(release(bob, 1), "Unauthorized");
+    await expectError(release(alice, 2), "MilestoneNotFound");
+
+    // Milestones don't have to go in order, but each goes out once
+    const bobTokensBefore = await tokenBalance(bobTokenAccount);
+    await release(alice, 1);
+    expect((await tokenBalance(bobTokenAccount)) - bobTokensBefore).to.equal(7_000_000);
+    await expectError(release(carol, 1), "MilestoneAlreadyReleased");
+
+    // The last one closes the escrow
+    await release(carol, 0);
+    expect((await tokenBalance(bobTokenAccount)) - bobTokensBefore).to.equal(10_000_000);
+    expect(await connection.getAccountInfo(vault)).to.be.null;
+    expect(await connection.getAccountInfo(escrowAccount)).to.be.null;
+  });
+
+  it("vests a grant linearly and lets Alice revoke the unvested rest", async () => {
+    const offerId = new BN(19);
+    const { escrowAccount, vault } = escrowPdas(offerId);
+
+    // 10 DED vesting to Bob over 1000 seconds, nothing before a short cliff
+    const startTs = await chainTime();
+    const cliffTs = startTs + 4;
+    await program.methods
+      .initializeVesting(
+        offerId,
+        new BN(10_000_000),
+        bob.publicKey,
+        new BN(startTs),
+        new BN(cliffTs),
+        new BN(startTs + 1_000),
+        true
+      )
+      .accountsPartial({
+        initializer: alice.publicKey,
+        mint,
+        initializerTokenAccount: aliceTokenAccount,
+        paymentMint: null,
+        escrowAccount,
+        vault,
+        config,
+        tokenProgram: TOKEN_PROGRAM_ID,
+        systemProgram: SystemProgram.programId,
+      })
+      .signers([alice])
+      .rpc();
+
+    const claim = (beneficiary: Keypair, beneficiaryTokenAccount: PublicKey) =>
+      program.methods
+        .claim()
+        .accountsPartial({
+          beneficiary: beneficiary.publicKey,
+          beneficiaryTokenAccount,
+          initializer: alice.publicKey,
+          vault,
+          escrowAccount,
+          mint,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .signers([beneficiary])
+        .rpc();
+
+    const revoke = () =>
+      program.methods
+        .revoke()
+        .accountsPartial({
+          initializer: alice.publicKey,
+          initializerTokenAccount: aliceTokenAccount,
+          vault,
+          escrowAccount,
+          mint,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .signers([alice])
+        .rpc();
+
+    await expectError(claim(bob, bobTokenAccount), "NothingToClaim");
+    await waitUntil(cliffTs);
+
+    // Only Bob claims, and only what has vested so far
+    await expectError(claim(carol, carolTokenAccount), "Unauthorized");
+    const bobTokensBefore = await tokenBalance(bobTokenAccount);
+    await claim(bob, bobTokenAccount);
+    const claimed = (await tokenBalance(bobTokenAccount)) - bobTokensBefore;
+    expect(claimed).to.be.greaterThan(0).and.lessThan(10_000_000);
+
+    // Let a little more vest, then revoke. The unvested rest goes back to Alice,
+    // the part that vested stays for Bob
+    await waitUntil(await chainTime());
+    const aliceTokensBefore = await tokenBalance(aliceTokenAccount);
+    await revoke();
+    const returned = (await tokenBalance(aliceTokenAccount)) - aliceTokensBefore;
+    expect(returned).to.be.greaterThan(0);
+    await expectError(revoke(), "AlreadyRevoked");
+
+    // Bob's last claim empties the vault and closes the escrow
+    await claim(bob, bobTokenAccount);
+    expect((await tokenBalance(bobTokenAccount)) - bobTokensBefore + returned).to.equal(10_000_000);
+    expect(await connection.getAccountInfo(vault)).to.be.null;
+    expect(await connection.getAccountInfo(escrowAccount)).to.be.null;
+  });
+
+  it("recovers escrows created before offer ids existed", async function () {
+    // Legacy escrows can't be created anymore, `anchor test` loads them from the
+    // fixtures listed in Anchor.toml. Clusters without them skip this test.
+    const fixture = (name: string) =>
+      JSON.parse(fs.readFileSync(path.join(__dirname, "fixtures", `${name}.json`), "utf8"));
+    const legacyKeypair = (name: string) => Keypair.fromSecretKey(Uint8Array.from(fixture(name)));
+    const legacyPdas = (initializer: PublicKey) => ({
+      escrowAccount: PublicKey.findProgramAddressSync([Buffer.from("escrow"), initializer.toBuffer()], program.programId)[0],
+      vault: PublicKey.findProgramAddressSync([Buffer.from("vault"), initializer.toBuffer()], program.programId)[0],
+    });
+
+    const legacyMint = new PublicKey(fixture("legacy-mint").pubkey);
+    const openTokenAccount = new PublicKey(fixture("legacy-open-token-account").pubkey);
+    const completed = legacyKeypair("legacy-completed-initializer");
+    const open = legacyKeypair("legacy-open-initializer");
+
+    if (!(await connection.getAccountInfo(legacyPdas(open.publicKey).escrowAccount))) {
+      this.skip();
+    }
+
+    const closeCompleted = (initializer: Keypair) =>
+      program.methods
+        .closeCompleted()
+        .accountsPartial({
+          initializer: initializer.publicKey,
+          ...legacyPdas(initializer.publicKey),
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .signers([initializer])
+        .rpc();
+
+    const cancelLegacy = (initializer: Keypair) =>
+      program.methods
+        .cancelLegacy()
+        .accountsPartial({
+          initializer: initializer.publicKey,
+          initializerTokenAccount: openTokenAccount,
+          ...legacyPdas(initializer.publicKey),
+          mint: legacyMint,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .signers([initializer])
+        .rpc();
+
+    // A filled escrow only has its rent left to reclaim
+    await expectError(cancelLegacy(completed), "AlreadyCompleted");
+    const completedPdas = legacyPdas(completed.publicKey);
+    const rent =
+      (await connection.getBalance(completedPdas.escrowAccount)) + (await connection.getBalance(completedPdas.vault));
+    await closeCompleted(completed);
+
+    expect(await connection.getBalance(completed.publicKey)).to.equal(rent);
+    expect(await connection.getAccountInfo(completedPdas.escrowAccount)).to.be.null;
+    expect(await connection.getAccountInfo(completedPdas.vault)).to.be.null;
+
+    // An open one gives the tokens back to Alice
+    await expectError(closeCompleted(open), "NotCompleted");
+    await cancelLegacy(open);
 
-  it("Is initialized!", async () => {
-    // Add your test here.
-    const tx = await program.methods.initialize().rpc();
-    console.log("Your transaction signature", tx);
+    expect(await tokenBalance(openTokenAccount)).to.equal(5_000_000);
+    expect(await connection.getAccountInfo(legacyPdas(open.publicKey).escrowAccount)).to.be.null;
+    expect(await connection.getAccountInfo(legacyPdas(open.publicKey).vault)).to.be.null;
   });
 });